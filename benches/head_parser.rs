@@ -0,0 +1,81 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use my_http_server::parser::{HeadParser, RequestHead};
+use my_http_server::utils::scan;
+
+const SMALL: &str = "GET /hello HTTP/1.1\r\nHost: localhost:8080\r\nAccept: */*\r\n\r\n";
+
+const BROWSER: &str = "GET /static/app.js?v=1.2.3 HTTP/1.1\r\n\
+Host: www.example.com\r\n\
+Connection: keep-alive\r\n\
+User-Agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36\r\n\
+Accept: text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8\r\n\
+Accept-Encoding: gzip, deflate, br\r\n\
+Accept-Language: zh-CN,zh;q=0.9,en;q=0.8\r\n\
+Cache-Control: max-age=0\r\n\
+Cookie: sid=4f8a2c1e9b7d6f5a3c2e1d0b9a8f7e6d5c4b3a2f1e0d9c8b7a6f5e4d3c2b1a0f; theme=dark; lang=zh\r\n\
+Referer: https://www.example.com/index.html\r\n\
+Sec-Fetch-Dest: script\r\n\
+Sec-Fetch-Mode: no-cors\r\n\
+Sec-Fetch-Site: same-origin\r\n\
+If-None-Match: \"5f3c-1a2b3c4d\"\r\n\
+If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n";
+
+/// 之前的实现：每次读到数据后查找\r\n\r\n，完整后转为字符串按行分割
+fn legacy(chunks: &[&[u8]]) -> usize {
+    let mut buffered = Vec::new();
+    let mut checked = 0;
+    for chunk in chunks {
+        buffered.extend_from_slice(chunk);
+        if let Some(pos) = scan(&buffered[checked..], b"\r\n\r\n") {
+            let end = checked + pos + 4;
+            let header = String::from_utf8(buffered[..end].to_vec()).unwrap();
+            let mut lines = header.lines();
+            let mut words = lines.next().unwrap().split_whitespace();
+            let (method, target, version) = (words.next(), words.next(), words.next());
+            let mut headers = Vec::new();
+            for line in lines {
+                let mut split = line.splitn(2, ':');
+                if let (Some(key), Some(value)) = (split.next(), split.next()) {
+                    headers.push((key.trim(), value.trim()));
+                }
+            }
+            black_box((method, target, version, &headers));
+            return headers.len();
+        }
+        checked = buffered.len().saturating_sub(3);
+    }
+    0
+}
+
+fn incremental(chunks: &[&[u8]]) -> usize {
+    let mut buffered = Vec::new();
+    let mut parser = HeadParser::new(64 * 1024);
+    for chunk in chunks {
+        buffered.extend_from_slice(chunk);
+        if let Some(head) = parser.parse(&buffered).unwrap() {
+            let raw = std::str::from_utf8(&buffered[..head.size()]).unwrap();
+            let head = RequestHead::new(raw, &head);
+            black_box(&head);
+            return head.headers().len();
+        }
+    }
+    0
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("head");
+    for (name, raw) in [("small", SMALL), ("browser", BROWSER)] {
+        // 一次读到整个请求头，以及每次只读到64字节
+        for chunk_size in [usize::MAX, 64] {
+            let chunks: Vec<&[u8]> = raw.as_bytes().chunks(chunk_size.min(raw.len())).collect();
+            assert_eq!(legacy(&chunks), incremental(&chunks));
+            let id = format!("{}/{}", name, if chunk_size == usize::MAX { "whole".to_string() } else { chunk_size.to_string() });
+            group.bench_with_input(BenchmarkId::new("legacy", &id), &chunks, |b, chunks| b.iter(|| legacy(chunks)));
+            group.bench_with_input(BenchmarkId::new("incremental", &id), &chunks, |b, chunks| b.iter(|| incremental(chunks)));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
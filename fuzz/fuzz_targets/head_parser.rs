@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use my_http_server::parser::{HeadParser, RequestHead};
+
+// 任意输入不会panic，按第一个字节决定的位置分开送入时结果与一次送入相同
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, raw)) = data.split_first() else {
+        return;
+    };
+    let whole = HeadParser::new(8192).parse(raw);
+    let mut parser = HeadParser::new(8192);
+    let split = split as usize % (raw.len() + 1);
+    let parts = match parser.parse(&raw[..split]) {
+        Ok(None) => parser.parse(raw),
+        result => result,
+    };
+    assert_eq!(whole, parts);
+    if let Ok(Some(head)) = whole {
+        if let Ok(text) = std::str::from_utf8(&raw[..head.size()]) {
+            let head = RequestHead::new(text, &head);
+            assert_eq!(RequestHead::parse(text), Ok(head));
+        }
+    }
+});
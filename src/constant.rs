@@ -1,9 +1,14 @@
 /// 支持的content-type
-pub const APPLICATION_X_WWW_FORM_URLENCODED: &'static str = "application/x-www-form-urlencoded";
-pub const MULTIPART_FORM_DATA: &'static str = "multipart/form-data";
-pub const APPLICATION_JSON: &'static str = "application/json";
-pub const APPLICATION_OCTET_STREAM: &'static str = "application-octet-stream";
-pub const TEXT_HTML: &'static str = "text/html";
-pub const TEXT_CSS: &'static str = "text/css";
-pub const TEXT_JAVASCRIPT: &'static str = "text/javascript";
-pub const TEXT_PLAIN: &'static str = "text/plain";
\ No newline at end of file
+pub const APPLICATION_X_WWW_FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+pub const MULTIPART_FORM_DATA: &str = "multipart/form-data";
+pub const APPLICATION_JSON: &str = "application/json";
+pub const APPLICATION_OCTET_STREAM: &str = "application/octet-stream";
+pub const TEXT_HTML: &str = "text/html";
+pub const TEXT_CSS: &str = "text/css";
+pub const TEXT_JAVASCRIPT: &str = "text/javascript";
+pub const TEXT_PLAIN: &str = "text/plain";
+pub const IMAGE_PNG: &str = "image/png";
+pub const IMAGE_JPEG: &str = "image/jpeg";
+pub const IMAGE_GIF: &str = "image/gif";
+pub const IMAGE_SVG: &str = "image/svg+xml";
+pub const IMAGE_ICON: &str = "image/x-icon";
\ No newline at end of file
@@ -0,0 +1,308 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::error::{Error, Result};
+use crate::header::HeaderMap;
+use crate::utils::{http_date, is_token};
+
+/// SameSite属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// 浏览器要求同时设置Secure
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// 响应中的Set-Cookie
+/// ```ignore
+/// let cookie = Cookie::new("theme", "dark").path("/").max_age(Duration::from_secs(3600)).http_only(true);
+/// response.set_cookie(&cookie);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// 名称或值不合法时panic，动态的内容使用[Cookie::try_new]
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self::try_new(name, value).unwrap()
+    }
+    /// 名称必须是token，值只能包含RFC 6265允许的字符
+    pub fn try_new(name: impl Into<String>, value: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        let value = value.into();
+        if !is_token(&name) {
+            return Err(Error::invalid(format!("非法的cookie名称 {:?}", name)));
+        }
+        if !value.bytes().all(is_cookie_octet) {
+            return Err(Error::invalid(format!("cookie {} 的值包含非法字符", name)));
+        }
+        Ok(Self {
+            name,
+            value,
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+    /// 删除浏览器中的cookie，路径和域名需要与设置时相同
+    pub fn removal(name: impl Into<String>) -> Self {
+        Self::new(name, "").max_age(Duration::ZERO).expires(UNIX_EPOCH)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+    /// 同时设置Expires时，浏览器以Max-Age为准
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+/// 按照Set-Cookie的格式输出，路径和域名中的非法字符会被去掉，防止注入其他属性
+impl Display for Cookie {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let attr = |v: &str| v.chars().filter(|c| *c != ';' && !c.is_control()).collect::<String>();
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", attr(path))?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", attr(domain))?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// RFC 6265 cookie-octet
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+/// 请求中的cookie，按出现顺序保存
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieJar {
+    cookies: Vec<(String, String)>,
+}
+
+impl CookieJar {
+    /// 解析所有Cookie头，格式不对的项忽略
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let cookies = headers.get_all("cookie")
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .filter(|(k, _)| !k.is_empty())
+            .map(|(k, v)| {
+                // 值可以用双引号括起来
+                let v = v.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(v);
+                (k.to_string(), v.to_string())
+            })
+            .collect();
+        Self { cookies }
+    }
+
+    /// 第一个同名cookie的值
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.cookies.iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+    /// 验证签名后的值，没有这个cookie或签名不对时返回None
+    pub fn get_signed(&self, name: &str, key: &CookieKey) -> Option<&str> {
+        key.verify(name, self.get(name)?)
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.cookies.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+}
+
+/// 签名cookie的密钥，值后面附加HMAC-SHA256签名，防止客户端篡改
+#[derive(Clone)]
+pub struct CookieKey {
+    secret: Vec<u8>,
+}
+
+impl CookieKey {
+    /// 密钥至少需要32字节
+    pub fn new(secret: impl Into<Vec<u8>>) -> Result<Self> {
+        let secret = secret.into();
+        if secret.len() < 32 {
+            return Err(Error::invalid("cookie密钥至少需要32字节"));
+        }
+        Ok(Self { secret })
+    }
+
+    fn mac(&self, name: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        // 名称也参与签名，防止把一个cookie的值用在另一个cookie上
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// 返回签名后的cookie，值的格式为 原值.签名
+    pub fn sign(&self, cookie: Cookie) -> Cookie {
+        let signature = self.mac(&cookie.name, &cookie.value).finalize().into_bytes();
+        let hex: String = signature.iter().map(|b| format!("{:02x}", b)).collect();
+        Cookie { value: format!("{}.{}", cookie.value, hex), ..cookie }
+    }
+
+    /// 验证签名，返回原值
+    pub fn verify<'a>(&self, name: &str, signed: &'a str) -> Option<&'a str> {
+        let (value, hex) = signed.rsplit_once('.')?;
+        if hex.len() != 64 {
+            return None;
+        }
+        let signature = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        // 常量时间比较
+        self.mac(name, value).verify_slice(&signature).ok()?;
+        Some(value)
+    }
+}
+
+impl std::fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str("CookieKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::header::HeaderMap;
+    use super::{Cookie, CookieJar, CookieKey, SameSite};
+
+    #[test]
+    fn set_cookie_attributes() {
+        let cookie = Cookie::new("sid", "abc123")
+            .path("/app")
+            .domain("example.com")
+            .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .max_age(Duration::from_secs(3600))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(cookie.to_string(), "sid=abc123; Path=/app; Domain=example.com; \
+Expires=Sun, 06 Nov 1994 08:49:37 GMT; Max-Age=3600; Secure; HttpOnly; SameSite=Lax");
+        assert_eq!(Cookie::removal("sid").to_string(), "sid=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0");
+        assert_eq!(Cookie::new("a", "b").path("/; Secure=no").to_string(), "a=b; Path=/ Secure=no");
+    }
+
+    #[test]
+    fn rejects_invalid_cookies() {
+        assert!(Cookie::try_new("a b", "1").is_err());
+        assert!(Cookie::try_new("a", "1;Path=/").is_err());
+        assert!(Cookie::try_new("a", "x y").is_err());
+        assert!(Cookie::try_new("a", "").is_ok());
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "a=1; b=\"two\"; broken; =x");
+        headers.append("Cookie", "a=3;c=");
+        let jar = CookieJar::parse(&headers);
+        assert_eq!(jar.iter().collect::<Vec<_>>(), [("a", "1"), ("b", "two"), ("a", "3"), ("c", "")]);
+        assert_eq!(jar.get("a"), Some("1"));
+        assert_eq!(jar.get("d"), None);
+    }
+
+    #[test]
+    fn signed_cookies() {
+        let key = CookieKey::new([7u8; 32]).unwrap();
+        let cookie = key.sign(Cookie::new("user", "42"));
+        assert!(cookie.value().starts_with("42."));
+        assert_eq!(key.verify("user", cookie.value()), Some("42"));
+        // 篡改值、换名称、换密钥都无法通过验证
+        let tampered = cookie.value().replacen("42", "43", 1);
+        assert_eq!(key.verify("user", &tampered), None);
+        assert_eq!(key.verify("admin", cookie.value()), None);
+        let other = CookieKey::new([8u8; 32]).unwrap();
+        assert_eq!(other.verify("user", cookie.value()), None);
+        assert_eq!(key.verify("user", "42"), None);
+        assert!(CookieKey::new("short").is_err());
+    }
+}
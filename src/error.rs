@@ -1,27 +1,279 @@
+use std::any::Any;
+use std::collections::HashMap;
 use std::error::Error as StdError;
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
 use std::result::Result as StdResult;
-
-pub type Error = Box<dyn StdError + Sync + Send>;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use serde_json::json;
+use crate::constant;
+use crate::parser::ParseError;
+use crate::response::{HttpResponse, HttpStatus};
 
 pub type Result<T> = StdResult<T, Error>;
 
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct Fail(pub String);
+/// 错误，每种错误对应一个响应状态码，见[Error::status]
+#[derive(Debug)]
+pub enum Error {
+    /// 请求格式错误，400
+    BadRequest(String),
+    /// 请求头解析或验证失败，超出大小为431，不支持的Transfer-Encoding为501，不支持的版本为505，其他为400
+    Parse(ParseError),
+    /// 读取请求超时，408
+    Timeout(String),
+    /// 请求体超出限制，413
+    PayloadTooLarge(String),
+    /// 参数或配置无效，如路由冲突、证书不匹配，500
+    Invalid(String),
+    /// 处理器panic，500
+    Panic(String),
+    /// 读写错误，500
+    Io(io::Error),
+    /// 其他库的错误，500
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl Error {
+    pub fn bad_request(msg: impl Into<String>) -> Self {
+        Error::BadRequest(msg.into())
+    }
+    pub fn timeout(msg: impl Into<String>) -> Self {
+        Error::Timeout(msg.into())
+    }
+    pub fn too_large(msg: impl Into<String>) -> Self {
+        Error::PayloadTooLarge(msg.into())
+    }
+    pub fn invalid(msg: impl Into<String>) -> Self {
+        Error::Invalid(msg.into())
+    }
+    pub fn other(err: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Error::Other(err.into())
+    }
+    /// panic的内容转换为错误
+    pub fn panic(payload: Box<dyn Any + Send>) -> Self {
+        let msg = match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => payload.downcast_ref::<&str>().unwrap_or(&"未知错误").to_string(),
+        };
+        Error::Panic(msg)
+    }
+
+    /// 对应的响应状态码
+    pub fn status(&self) -> HttpStatus {
+        match self {
+            Error::BadRequest(_) => HttpStatus::BadRequest,
+            Error::Parse(ParseError::TooLarge) => HttpStatus::RequestHeaderFieldsTooLarge,
+            Error::Parse(ParseError::UnsupportedTransferEncoding) => HttpStatus::NotImplemented,
+            Error::Parse(ParseError::UnsupportedVersion) => HttpStatus::HttpVersionNotSupported,
+            Error::Parse(_) => HttpStatus::BadRequest,
+            Error::Timeout(_) => HttpStatus::RequestTimeout,
+            Error::PayloadTooLarge(_) => HttpStatus::ContentTooLarge,
+            Error::Invalid(_) | Error::Panic(_) | Error::Io(_) | Error::Other(_) => HttpStatus::InternalServerError,
+        }
+    }
+    /// 可以返回给客户端的原因，服务器内部的错误只返回状态码的原因短语，不泄露细节
+    pub fn reason(&self) -> String {
+        let status = self.status();
+        match status.code() {
+            500.. => status.reason().to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Error::BadRequest(msg) | Error::Timeout(msg) | Error::PayloadTooLarge(msg) | Error::Invalid(msg) => {
+                f.write_str(msg)
+            }
+            Error::Parse(err) => Display::fmt(err, f),
+            Error::Panic(msg) => write!(f, "处理请求时panic: {}", msg),
+            Error::Io(err) => Display::fmt(err, f),
+            Error::Other(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Parse(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Other(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// 其他库的错误
+macro_rules! from_other {
+    ($($err:ty),*) => {
+        $(impl From<$err> for Error {
+            fn from(err: $err) -> Self {
+                Error::Other(Box::new(err))
+            }
+        })*
+    };
+}
+
+from_other!(std::str::Utf8Error, std::string::FromUtf8Error, std::time::SystemTimeError, serde_json::Error, h2::Error, http::Error,
+    tokio_rustls::rustls::Error);
+
+/// 自定义错误响应的函数
+type RenderFn = dyn Fn(&Error) -> HttpResponse + Send + Sync;
+
+/// 错误响应的内容，默认是纯文本的原因
+/// ```ignore
+/// http_settings.error_pages = ErrorPages::new()
+///     .json()
+///     .page(HttpStatus::InternalServerError, "<h1>服务器错误</h1>");
+/// ```
+#[derive(Clone, Default)]
+pub struct ErrorPages {
+    json: bool,
+    pages: HashMap<u16, Arc<[u8]>>,
+    custom: Option<Arc<RenderFn>>,
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 使用JSON格式 {"code": 400, "msg": "原因"}
+    pub fn json(mut self) -> Self {
+        self.json = true;
+        self
+    }
+    /// 某个状态码使用固定的HTML页面，优先于JSON
+    pub fn page(mut self, status: HttpStatus, html: impl Into<Vec<u8>>) -> Self {
+        self.pages.insert(status.code(), html.into().into());
+        self
+    }
+    /// 完全自定义响应，优先于其他设置
+    pub fn custom(mut self, render: impl Fn(&Error) -> HttpResponse + Send + Sync + 'static) -> Self {
+        self.custom = Some(Arc::new(render));
+        self
+    }
+
+    /// 生成错误响应
+    pub fn render(&self, err: &Error) -> HttpResponse {
+        if let Some(render) = &self.custom {
+            return render(err);
+        }
+        let status = err.status();
+        if let Some(page) = self.pages.get(&status.code()) {
+            let mut response = HttpResponse::new(status, None, Some(page.to_vec()));
+            response.set_header("Content-Type", constant::TEXT_HTML);
+            return response;
+        }
+        if self.json {
+            return HttpResponse::json(status.clone(), &json!({ "code": status.code(), "msg": err.reason() }));
+        }
+        HttpResponse::new(status, None, Some(err.reason().into_bytes()))
+    }
+}
 
-impl Fail {
-    pub fn new<E>(err: E) -> Box<Self> where E: Display {
-        Box::new(Fail(err.to_string()))
+impl Debug for ErrorPages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("ErrorPages")
+            .field("json", &self.json)
+            .field("pages", &self.pages.keys().collect::<Vec<_>>())
+            .field("custom", &self.custom.is_some())
+            .finish()
     }
-    pub fn from<T, E>(err: E) -> Result<T> where E: Display {
-        Err(Self::new(err))
+}
+
+/// 捕获future执行中的panic，转换为[Error::Panic]
+pub(crate) struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> CatchUnwind<F> {
+    pub(crate) fn new(future: F) -> Self {
+        CatchUnwind(Box::pin(future))
+    }
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.0.as_mut();
+        match catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(Error::panic(payload))),
+        }
     }
 }
 
-impl StdError for Fail {}
+#[cfg(test)]
+mod tests {
+    use crate::parser::ParseError;
+    use crate::response::{Body, HttpResponse, HttpStatus};
+    use super::{CatchUnwind, Error, ErrorPages};
+
+    fn body(response: &HttpResponse) -> &[u8] {
+        match response.body() {
+            Body::Bytes(bytes) => bytes,
+            _ => &[],
+        }
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(Error::bad_request("x").status(), HttpStatus::BadRequest);
+        assert_eq!(Error::from(ParseError::MissingHost).status(), HttpStatus::BadRequest);
+        assert_eq!(Error::from(ParseError::TooLarge).status(), HttpStatus::RequestHeaderFieldsTooLarge);
+        assert_eq!(Error::from(ParseError::UnsupportedTransferEncoding).status(), HttpStatus::NotImplemented);
+        assert_eq!(Error::from(ParseError::UnsupportedVersion).status(), HttpStatus::HttpVersionNotSupported);
+        assert_eq!(Error::timeout("x").status(), HttpStatus::RequestTimeout);
+        assert_eq!(Error::too_large("x").status(), HttpStatus::ContentTooLarge);
+        assert_eq!(Error::invalid("x").status(), HttpStatus::InternalServerError);
+        // 服务器内部的错误不返回细节
+        assert_eq!(Error::invalid("密钥错误").reason(), "Internal Server Error");
+        assert_eq!(Error::from(ParseError::MissingHost).reason(), "HTTP/1.1请求缺少Host");
+    }
+
+    #[test]
+    fn error_pages() {
+        let err = Error::bad_request("缺少参数");
+        let response = ErrorPages::new().render(&err);
+        assert_eq!(response.status(), &HttpStatus::BadRequest);
+        assert_eq!(body(&response), "缺少参数".as_bytes());
+        let pages = ErrorPages::new().json().page(HttpStatus::InternalServerError, "<h1>500</h1>");
+        let response = pages.render(&err);
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(body(&response), r#"{"code":400,"msg":"缺少参数"}"#.as_bytes());
+        let response = pages.render(&Error::Panic("boom".to_string()));
+        assert_eq!(response.header("content-type"), Some("text/html"));
+        assert_eq!(body(&response), b"<h1>500</h1>");
+        let pages = pages.custom(|err| HttpResponse::new(HttpStatus::ImATeapot, None, Some(err.to_string().into_bytes())));
+        assert_eq!(pages.render(&err).status(), &HttpStatus::ImATeapot);
+    }
 
-impl Display for Fail {
-    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
-        write!(formatter, "{}", self.0)
+    #[tokio::test]
+    async fn catches_panics() {
+        assert_eq!(CatchUnwind::new(async { 1 }).await.unwrap(), 1);
+        let err = CatchUnwind::new(async {
+            tokio::task::yield_now().await;
+            panic!("boom {}", 1);
+        }).await.unwrap_err();
+        assert_eq!(err.to_string(), "处理请求时panic: boom 1");
     }
 }
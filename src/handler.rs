@@ -1,63 +1,238 @@
-use std::{env, fs};
-use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use serde_json::json;
 use crate::constant;
-use crate::request::{HttpMethod, HttpRequest};
-use crate::response::{HttpResponse, HttpStatus};
+use crate::header::HeaderMap;
+use crate::parser::RequestHead;
+use crate::request::HttpRequest;
+use crate::response::{Body, HttpResponse, HttpStatus};
+use crate::state::State;
+use crate::utils::percent_decode;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 /// handler接口
-pub trait Handler {
-    fn handle<'a>(req: &HttpRequest) -> HttpResponse<'a>;
-    fn load_file(file_name: &str) -> Option<Vec<u8>> {
-        let file_path = format!("{}/static/{}", env!("CARGO_MANIFEST_DIR"), file_name);
-        fs::read(file_path).ok()
+pub trait Handler: Send + Sync {
+    fn handle<'a>(&'a self, req: &'a HttpRequest<'a>, state: &'a State) -> BoxFuture<'a, HttpResponse>;
+    /// 请求带有`Expect: 100-continue`时，在客户端发送请求体之前调用
+    /// 返回响应时直接拒绝请求，客户端不会发送请求体；默认接受
+    fn expect_continue(&self, _head: &RequestHead, _state: &State) -> Option<HttpResponse> {
+        None
     }
 }
 
-/// 静态资源处理器
-pub struct StaticHandler;
+/// 闭包处理器，见[handler_fn]
+pub struct FnHandler<F>(F);
 
-impl Handler for StaticHandler {
-    fn handle<'a>(req: &HttpRequest) -> HttpResponse<'a> {
-        let route: Vec<&str> = req.url().split("/").collect();
-        match route[1] {
-            // 访问"/"等于访问"/index.html"
-            "" => {
-                let mut headers = BTreeMap::new();
-                headers.insert("Content-Type", constant::TEXT_HTML);
-                HttpResponse::new(HttpStatus::Ok,
-                                  Some(headers),
-                                  Self::load_file("index.html"))
+impl<F> Handler for FnHandler<F>
+    where F: for<'a> Fn(&'a HttpRequest<'a>, &'a State) -> BoxFuture<'a, HttpResponse> + Send + Sync {
+    fn handle<'a>(&'a self, req: &'a HttpRequest<'a>, state: &'a State) -> BoxFuture<'a, HttpResponse> {
+        (self.0)(req, state)
+    }
+}
+
+/// 将闭包转换为处理器，闭包返回`Box::pin(async move { ... })`
+pub fn handler_fn<F>(f: F) -> FnHandler<F>
+    where F: for<'a> Fn(&'a HttpRequest<'a>, &'a State) -> BoxFuture<'a, HttpResponse> + Send + Sync {
+    FnHandler(f)
+}
+
+/// 静态资源处理器，把请求路径映射为根目录下的文件
+/// 访问目录时返回其中的index.html，文件不存在时返回根目录下的404.html
+/// ```ignore
+/// router.get("/assets/*path", StaticHandler::new("./public").prefix("/assets"))?;
+/// ```
+pub struct StaticHandler {
+    root: PathBuf,
+    prefix: String,
+}
+
+impl StaticHandler {
+    /// 以`root`为根目录，相对路径相对于运行时的工作目录
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into(), prefix: String::new() }
+    }
+    /// 挂载的路径前缀，如 /assets，请求路径去掉前缀后再查找文件
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// 请求路径转换为根目录下的相对路径
+    /// 先百分号解码再按 / 分割，编码的 .. 和 / 同样会被检查
+    fn relative_path(&self, url: &str) -> Result<PathBuf, HttpStatus> {
+        let rest = url.strip_prefix(self.prefix.as_str()).ok_or(HttpStatus::NotFound)?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return Err(HttpStatus::NotFound);
+        }
+        let decoded = String::from_utf8(percent_decode(rest.as_bytes())).map_err(|_| HttpStatus::BadRequest)?;
+        if decoded.contains(['\0', '\\']) {
+            return Err(HttpStatus::BadRequest);
+        }
+        let mut path = PathBuf::new();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(HttpStatus::BadRequest),
+                // 只允许普通的文件名，排除Windows的盘符等
+                _ => match Path::new(segment).components().collect::<Vec<_>>()[..] {
+                    [Component::Normal(_)] => path.push(segment),
+                    _ => return Err(HttpStatus::BadRequest),
+                },
             }
-            path => match Self::load_file(path) {
-                Some(contents) => {
-                    let mut headers = BTreeMap::new();
-                    if path.ends_with(".css") {
-                        headers.insert("Content-Type", constant::TEXT_CSS);
-                    } else if path.ends_with(".js") {
-                        headers.insert("Content-Type", constant::TEXT_JAVASCRIPT);
-                    } else {
-                        headers.insert("Content-Type", constant::TEXT_HTML);
-                    }
-                    HttpResponse::new(HttpStatus::Ok, Some(headers), Some(contents))
+        }
+        Ok(path)
+    }
+
+    /// 查找请求的文件，通过符号链接指向根目录之外的文件视为不存在
+    async fn resolve(&self, relative: &Path) -> Option<PathBuf> {
+        let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+        let mut path = tokio::fs::canonicalize(root.join(relative)).await.ok()?;
+        if tokio::fs::metadata(&path).await.ok()?.is_dir() {
+            path = tokio::fs::canonicalize(path.join("index.html")).await.ok()?;
+        }
+        path.starts_with(&root).then_some(path)
+    }
+
+    async fn not_found(&self) -> HttpResponse {
+        let page = match self.resolve(Path::new("404.html")).await {
+            Some(path) => tokio::fs::read(path).await.ok(),
+            None => None,
+        };
+        HttpResponse::not_found(page)
+    }
+}
+
+impl Handler for StaticHandler {
+    fn handle<'a>(&'a self, req: &'a HttpRequest<'a>, _state: &'a State) -> BoxFuture<'a, HttpResponse> {
+        Box::pin(async move {
+            let relative = match self.relative_path(req.url()) {
+                Ok(relative) => relative,
+                Err(HttpStatus::NotFound) => return self.not_found().await,
+                Err(status) => return HttpResponse::new(status, None, None),
+            };
+            let Some(path) = self.resolve(&relative).await else {
+                return self.not_found().await;
+            };
+            match Body::file(&path).await {
+                Ok(body) => {
+                    let mut headers = HeaderMap::new();
+                    headers.insert("Content-Type", content_type(&path));
+                    HttpResponse::new(HttpStatus::Ok, Some(headers), body)
                 }
-                None => HttpResponse::not_found(Self::load_file("404.html")),
+                Err(_) => self.not_found().await,
             }
-        }
+        })
+    }
+}
+
+/// 根据扩展名确定content-type
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => constant::TEXT_HTML,
+        "css" => constant::TEXT_CSS,
+        "js" | "mjs" => constant::TEXT_JAVASCRIPT,
+        "json" => constant::APPLICATION_JSON,
+        "txt" => constant::TEXT_PLAIN,
+        "png" => constant::IMAGE_PNG,
+        "jpg" | "jpeg" => constant::IMAGE_JPEG,
+        "gif" => constant::IMAGE_GIF,
+        "svg" => constant::IMAGE_SVG,
+        "ico" => constant::IMAGE_ICON,
+        _ => constant::APPLICATION_OCTET_STREAM,
     }
 }
 
 pub struct HelloHandler;
 
 impl Handler for HelloHandler {
-    fn handle<'a>(req: &HttpRequest) -> HttpResponse<'a> {
-        match req.method() {
-            HttpMethod::Get => {
-                let str = "{\"code\":200, \"msg\":\"OK\"}";
-                let mut headers = BTreeMap::new();
-                headers.insert("Content-Type", constant::APPLICATION_JSON);
-                HttpResponse::new(HttpStatus::Ok, Some(headers), Some(str.as_bytes().to_vec()))
-            }
-            _ => HttpResponse::not_found(Self::load_file("404.html"))
+    fn handle<'a>(&'a self, _req: &'a HttpRequest<'a>, _state: &'a State) -> BoxFuture<'a, HttpResponse> {
+        Box::pin(async move {
+            HttpResponse::json(HttpStatus::Ok, &json!({ "code": 200, "msg": "OK" }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::request::HttpRequest;
+    use crate::response::HttpStatus;
+    use crate::router::Router;
+    use super::StaticHandler;
+
+    /// 每个测试使用单独的临时目录，返回根目录，根目录之外有一个不能访问的文件
+    fn site(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("my-http-server-static-{}-{}", name, std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs/guide")).unwrap();
+        std::fs::write(root.join("index.html"), "home").unwrap();
+        std::fs::write(root.join("404.html"), "missing").unwrap();
+        std::fs::write(root.join("docs/index.html"), "docs").unwrap();
+        std::fs::write(root.join("docs/guide/intro.txt"), "intro").unwrap();
+        std::fs::write(root.join("docs/guide/a b.css"), "css").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        {
+            let _ = std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("leak.txt"));
+            let _ = std::os::unix::fs::symlink(&dir, root.join("up"));
+            let _ = std::os::unix::fs::symlink(root.join("docs/guide/intro.txt"), root.join("intro.txt"));
+        }
+        root
+    }
+
+    async fn get(router: &Router, url: &str) -> (HttpStatus, String, String) {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", url);
+        let req = HttpRequest::from(&raw, Vec::new(), "127.0.0.1").unwrap();
+        let response = router.route(req).await;
+        let status = response.status().clone();
+        let content_type = response.header("content-type").unwrap_or_default().to_string();
+        // 文件在写出时才读取
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).await.unwrap();
+        let bytes = String::from_utf8(bytes).unwrap();
+        (status, content_type, bytes.split_once("\r\n\r\n").unwrap().1.to_string())
+    }
+
+    #[tokio::test]
+    async fn serves_nested_files() {
+        let mut router = Router::new();
+        let root = site("nested");
+        router.get("/assets/*path", StaticHandler::new(&root).prefix("/assets/")).unwrap();
+        let ok = |body: &str, content_type: &str| (HttpStatus::Ok, content_type.to_string(), body.to_string());
+        assert_eq!(get(&router, "/assets/").await, ok("home", "text/html"));
+        assert_eq!(get(&router, "/assets/docs").await, ok("docs", "text/html"));
+        assert_eq!(get(&router, "/assets/docs/guide/intro.txt").await, ok("intro", "text/plain"));
+        assert_eq!(get(&router, "/assets/docs/guide/a%20b.css").await, ok("css", "text/css"));
+        assert_eq!(get(&router, "/assets/docs%2Fguide%2Fintro.txt").await, ok("intro", "text/plain"));
+        assert_eq!(get(&router, "/assets//docs/./guide/intro.txt").await, ok("intro", "text/plain"));
+        let missing = (HttpStatus::NotFound, "text/html".to_string(), "missing".to_string());
+        assert_eq!(get(&router, "/assets/docs/none.txt").await, missing);
+        assert_eq!(get(&router, "/assets/docs/guide").await, missing);
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_traversal() {
+        let mut router = Router::new();
+        let root = site("traversal");
+        router.get("/*path", StaticHandler::new(&root)).unwrap();
+        for url in ["/../secret.txt", "/docs/../../secret.txt", "/%2e%2e/secret.txt", "/%2E%2E%2Fsecret.txt",
+            "/docs/..%2f..%2fsecret.txt", "/docs%5c..%5c..%5csecret.txt", "/index.html%00.txt"] {
+            assert_eq!(get(&router, url).await.0, HttpStatus::BadRequest, "{}", url);
+        }
+        // 双重编码只解码一次，作为普通文件名
+        assert_eq!(get(&router, "/%252e%252e/secret.txt").await.0, HttpStatus::NotFound);
+        #[cfg(unix)]
+        {
+            // 符号链接指向根目录之外
+            assert_eq!(get(&router, "/leak.txt").await.0, HttpStatus::NotFound);
+            assert_eq!(get(&router, "/up/secret.txt").await.0, HttpStatus::NotFound);
+            // 根目录之内的符号链接可以访问
+            assert_eq!(get(&router, "/intro.txt").await.2, "intro");
         }
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
     }
 }
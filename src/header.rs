@@ -0,0 +1,261 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use crate::error::{Error, Result};
+use crate::utils::is_token;
+
+/// http头，名称不区分大小写，保留插入顺序，同名的头可以有多个值
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 获取第一个值
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    /// 获取所有值，如多个Set-Cookie
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        self.entries.iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+    /// 获取以逗号分隔的列表值，如Accept、Connection，多行的值会合并
+    pub fn get_list<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    }
+    /// 列表值中是否包含某一项，不区分大小写
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_list(name).any(|v| v.eq_ignore_ascii_case(token))
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// 设置值，替换所有同名的旧值，名称或值不合法时panic，动态的内容使用[HeaderMap::try_insert]
+    pub fn insert(&mut self, name: &str, value: impl Into<String>) {
+        self.try_insert(name, value).unwrap()
+    }
+    /// 设置值，替换所有同名的旧值，保留第一个旧值的位置
+    pub fn try_insert(&mut self, name: &str, value: impl Into<String>) -> Result<()> {
+        let value = value.into();
+        validate(name, &value)?;
+        let mut value = Some(value);
+        self.entries.retain_mut(|(k, v)| {
+            if !k.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            // 第一个旧值被替换，其余的删除
+            match value.take() {
+                Some(new) => {
+                    *v = new;
+                    true
+                }
+                None => false,
+            }
+        });
+        if let Some(value) = value {
+            self.entries.push((name.to_string(), value));
+        }
+        Ok(())
+    }
+    /// 追加值，名称或值不合法时panic，动态的内容使用[HeaderMap::try_append]
+    pub fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.try_append(name, value).unwrap()
+    }
+    /// 追加值，不影响同名的旧值
+    pub fn try_append(&mut self, name: &str, value: impl Into<String>) -> Result<()> {
+        let value = value.into();
+        validate(name, &value)?;
+        self.entries.push((name.to_string(), value));
+        Ok(())
+    }
+    /// 删除所有同名的值
+    pub fn remove(&mut self, name: &str) -> Vec<String> {
+        let mut removed = Vec::new();
+        self.entries.retain(|(k, v)| {
+            if k.eq_ignore_ascii_case(name) {
+                removed.push(v.clone());
+                false
+            } else { true }
+        });
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Content-Type，不包含参数部分
+    pub fn content_type(&self) -> Option<&str> {
+        self.get("content-type").map(media_type)
+    }
+    /// Content-Type中的参数，如charset、boundary
+    pub fn content_type_param(&self, name: &str) -> Option<Cow<'_, str>> {
+        param(self.get("content-type")?, name)
+    }
+    /// Content-Length，不是合法数字时返回None
+    pub fn content_length(&self) -> Option<u64> {
+        self.get("content-length")?.trim().parse().ok()
+    }
+    pub fn host(&self) -> Option<&str> {
+        self.get("host")
+    }
+    pub fn user_agent(&self) -> Option<&str> {
+        self.get("user-agent")
+    }
+}
+
+/// 按照 名称: 值\r\n 的格式输出
+impl Display for HeaderMap {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        for (k, v) in &self.entries {
+            write!(formatter, "{}: {}\r\n", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// 带参数的头的值中 ; 之前的部分，如Content-Type的媒体类型
+pub fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or_default().trim()
+}
+
+/// 带参数的头的值中的参数，名称不区分大小写
+/// 值可以是token或带引号的字符串，引号中可以有 ; 和转义字符
+pub fn param<'a>(value: &'a str, name: &str) -> Option<Cow<'a, str>> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')
+            .map(|(k, v)| (k, Some(v)))
+            .unwrap_or((rest, None));
+        // 没有值的参数
+        if let Some(pos) = key.find(';') {
+            rest = &rest[pos + 1..];
+            continue;
+        }
+        let after = after?.trim_start();
+        let (val, next) = if let Some(quoted) = after.strip_prefix('"') {
+            let (val, len) = unquote(quoted)?;
+            (val, &quoted[len..])
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            (Cow::Borrowed(after[..end].trim()), &after[end..])
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(val);
+        }
+        rest = next.split_once(';')?.1;
+    }
+}
+
+/// 解析引号之后的quoted-string，返回内容和包括结束引号在内的长度
+fn unquote(s: &str) -> Option<(Cow<'_, str>, usize)> {
+    let mut escaped = None::<String>;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let val = escaped.map(Cow::Owned).unwrap_or(Cow::Borrowed(&s[..i]));
+                return Some((val, i + 1));
+            }
+            '\\' => {
+                let (_, next) = chars.next()?;
+                escaped.get_or_insert_with(|| s[..i].to_string()).push(next);
+            }
+            c => {
+                if let Some(escaped) = &mut escaped {
+                    escaped.push(c);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// 名称必须是token，值不能包含CR、LF等控制字符，防止头注入
+fn validate(name: &str, value: &str) -> Result<()> {
+    if !is_token(name) {
+        return Err(Error::invalid(format!("非法的头名称 {:?}", name)));
+    }
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(Error::invalid(format!("头 {} 的值包含非法字符", name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeaderMap;
+
+    #[test]
+    fn case_insensitive_multi_value() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Content-Type", "text/html; charset=utf-8");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(headers.get_all("SET-COOKIE").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(headers.content_type(), Some("text/html"));
+        assert_eq!(headers.content_type_param("charset").as_deref(), Some("utf-8"));
+        assert_eq!(headers.to_string(), "Set-Cookie: a=1\r\nContent-Type: text/html; charset=utf-8\r\nset-cookie: b=2\r\n");
+    }
+
+    #[test]
+    fn parameters() {
+        use super::param;
+        let value = "form-data; flag; name=\"a;b\"; filename=\"x \\\"y\\\".txt\"; Size = 3";
+        assert_eq!(param(value, "name").as_deref(), Some("a;b"));
+        assert_eq!(param(value, "FILENAME").as_deref(), Some("x \"y\".txt"));
+        assert_eq!(param(value, "size").as_deref(), Some("3"));
+        assert_eq!(param(value, "flag"), None);
+        assert_eq!(param("text/plain", "charset"), None);
+    }
+
+    #[test]
+    fn insert_replaces_in_place() {
+        let mut headers = HeaderMap::new();
+        headers.append("Accept", "text/html");
+        headers.append("Host", "example.com");
+        headers.append("accept", "application/json");
+        headers.insert("ACCEPT", "*/*");
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("Accept", "*/*"), ("Host", "example.com")]);
+        assert_eq!(headers.remove("host"), ["example.com"]);
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn list_values() {
+        let mut headers = HeaderMap::new();
+        headers.append("Connection", "Keep-Alive, Upgrade");
+        headers.append("Connection", "close");
+        assert_eq!(headers.get_list("connection").collect::<Vec<_>>(), ["Keep-Alive", "Upgrade", "close"]);
+        assert!(headers.has_token("connection", "keep-alive"));
+        assert!(!headers.has_token("connection", "te"));
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        let mut headers = HeaderMap::new();
+        assert!(headers.try_insert("Location", "/a\r\nSet-Cookie: x=1").is_err());
+        assert!(headers.try_append("X-Test", "a\nb").is_err());
+        assert!(headers.try_append("Bad Name", "a").is_err());
+        assert!(headers.try_append("", "a").is_err());
+        assert!(headers.try_append("X-Tab", "a\tb").is_ok());
+        assert_eq!(headers.len(), 1);
+    }
+}
@@ -0,0 +1,536 @@
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::{Request, Response};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use crate::error::{Error, Result};
+use crate::parser::RequestHead;
+use crate::request::{HttpRequest, HttpVersion};
+use crate::response::{Body, HttpResponse};
+use crate::router::Router;
+use crate::server::{self, BodySink, HttpSettings, Shutdown, Stream};
+
+/// HTTP/2连接序言
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// 只用于HTTP/1.x的连接相关的头，HTTP/2中不能出现，Content-Length由请求体或响应体决定
+const SKIPPED_HEADERS: [&str; 7] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "http2-settings", "content-length"];
+
+// 帧类型和标志
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_CONTINUATION: u8 = 0x9;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+// 对方未设置时的最大帧大小
+const DEFAULT_MAX_FRAME_SIZE: usize = 16384;
+
+/// 处理一个HTTP/2连接，每个流在单独的任务中处理，响应与HTTP/1.x使用同一个路由
+pub(crate) async fn serve(http_settings: Arc<HttpSettings>,
+                          router: Arc<Router>,
+                          stream: impl Stream,
+                          addr: SocketAddr,
+                          mut shutdown: Shutdown) -> Result<()> {
+    let conn = h2::server::Builder::new()
+        .max_concurrent_streams(http_settings.max_concurrent_streams)
+        .max_header_list_size(http_settings.max_header_size as u32)
+        .handshake::<_, Bytes>(stream);
+    // 握手受header_timeout限制
+    let mut conn = timeout(http_settings.header_timeout, conn).await
+        .map_err(|_| Error::timeout("HTTP/2握手超时"))??;
+    let ip = addr.ip().to_string();
+    let mut closing = false;
+    // 连接上进行中的流，连接的任务在关闭超时后被取消时，drop会取消所有流
+    let mut streams = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept() => accepted,
+            // 发送GOAWAY，不再接受新的流，已有的流处理完后连接关闭
+            _ = shutdown.wait(), if !closing => {
+                closing = true;
+                conn.graceful_shutdown();
+                continue;
+            }
+            // 回收已完成的流
+            Some(_) = streams.join_next(), if !streams.is_empty() => continue,
+        };
+        let Some(accepted) = accepted else {
+            break;
+        };
+        let (request, respond) = accepted?;
+        let http_settings = http_settings.clone();
+        let router = router.clone();
+        let ip = ip.clone();
+        streams.spawn(async move {
+            if let Err(err) = serve_stream(&http_settings, &router, request, respond, &ip).await {
+                println!("{}", err);
+            }
+        });
+    }
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn serve_stream(http_settings: &HttpSettings,
+                      router: &Router,
+                      request: Request<RecvStream>,
+                      mut respond: SendResponse<Bytes>,
+                      ip: &str) -> Result<()> {
+    let head_only = request.method() == http::Method::HEAD;
+    let (parts, body) = request.into_parts();
+    // 转换为HTTP/1.x格式的请求头，与HTTP/1.x使用同样的解析
+    let response = match request_head(&parts) {
+        Ok(head) => match read_request(http_settings, head, body, ip).await {
+            Ok(request) => server::respond(http_settings, router, request).await,
+            Err(err) => http_settings.error_pages.render(&err),
+        },
+        Err(err) => http_settings.error_pages.render(&err),
+    };
+    send_response(response, head_only, &mut respond).await
+}
+
+/// 转换为与HTTP/1.x相同的请求头，:authority转换为Host
+fn request_head(parts: &http::request::Parts) -> Result<RequestHead<'_>> {
+    let target = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut headers = Vec::with_capacity(parts.headers.len() + 1);
+    let authority = parts.uri.authority();
+    if let Some(authority) = authority {
+        headers.push(("host", authority.as_str()));
+    }
+    for (name, value) in &parts.headers {
+        if authority.is_some() && name == http::header::HOST {
+            continue;
+        }
+        let value = value.to_str().map_err(|_| Error::bad_request(format!("头 {} 的值包含非法字符", name)))?;
+        headers.push((name.as_str(), value.trim()));
+    }
+    Ok(RequestHead::from_parts(parts.method.as_str(), target, "HTTP/2.0", headers))
+}
+
+/// 读取请求体并解析请求
+async fn read_request<'a>(http_settings: &HttpSettings,
+                          head: RequestHead<'a>,
+                          mut body: RecvStream,
+                          ip: &'a str) -> Result<HttpRequest<'a>> {
+    let mut sink = BodySink::new(http_settings, &head)?;
+    let limit = sink.limit(http_settings);
+    let mut size = 0;
+    loop {
+        // 两次收到数据之间的间隔受body_timeout限制
+        let data = match timeout(http_settings.body_timeout, body.data()).await {
+            Ok(Some(data)) => data?,
+            Ok(None) => break,
+            Err(_) => return Err(Error::timeout("读取请求体超时")),
+        };
+        size += data.len();
+        if size > limit {
+            return Err(Error::too_large("请求体大小超出限制"));
+        }
+        body.flow_control().release_capacity(data.len())?;
+        sink.write(&data).await?;
+    }
+    let trailers = body.trailers().await?
+        .map(|trailers| trailers.iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect())
+        .unwrap_or_default();
+    let mut request = HttpRequest::parse(head, sink.finish()?, ip, http_settings.multipart_limits())?;
+    request.set_trailers(trailers);
+    Ok(request)
+}
+
+async fn send_response(response: HttpResponse, head_only: bool, respond: &mut SendResponse<Bytes>) -> Result<()> {
+    let (status, headers, body) = response.into_parts();
+    let mut builder = Response::builder().status(status.code());
+    for (name, value) in headers.iter() {
+        if SKIPPED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+        builder = builder.header(name, value);
+    }
+    let allows_body = status.allows_body();
+    if let (true, Some(len)) = (allows_body, body.len()) {
+        builder = builder.header(http::header::CONTENT_LENGTH, len);
+    }
+    let end_of_stream = head_only || !allows_body || body.len() == Some(0);
+    let mut send = respond.send_response(builder.body(())?, end_of_stream)?;
+    if end_of_stream {
+        return Ok(());
+    }
+    match body {
+        Body::Empty => {}
+        Body::Bytes(bytes) => send_data(&mut send, Bytes::from(bytes)).await?,
+        Body::File(file, len) => send_reader(&mut send, file.take(len)).await?,
+        Body::Stream(reader) => send_reader(&mut send, reader).await?,
+    }
+    send.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+/// 按照流量控制窗口分段发送
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = poll_fn(|cx| send.poll_capacity(cx)).await
+            .ok_or_else(|| Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "流已关闭")))??;
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+async fn send_reader(send: &mut SendStream<Bytes>, mut reader: impl AsyncRead + Unpin) -> Result<()> {
+    let mut buf = vec![0u8; DEFAULT_MAX_FRAME_SIZE];
+    loop {
+        let length = reader.read(&mut buf).await?;
+        if length == 0 {
+            return Ok(());
+        }
+        send_data(send, Bytes::copy_from_slice(&buf[..length])).await?;
+    }
+}
+
+/// 读取开头的字节判断是否是HTTP/2连接序言，读到的字节留在`buffered`中
+pub(crate) async fn read_preface(stream: &mut impl Stream, buffered: &mut Vec<u8>) -> bool {
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = buffered.len().min(PREFACE.len());
+        if buffered[..len] != PREFACE[..len] {
+            return false;
+        }
+        if len == PREFACE.len() {
+            return true;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return false,
+            Ok(length) => buffered.extend_from_slice(&buf[..length]),
+        }
+    }
+}
+
+/// 通过 Upgrade: h2c 升级的请求，升级后作为流1处理
+#[derive(Debug)]
+pub(crate) struct Upgrade {
+    // HTTP2-Settings中的设置
+    settings: Vec<u8>,
+    // 请求转换成的HPACK头块
+    header_block: Vec<u8>,
+}
+
+/// 判断是否是h2c升级请求
+/// 只升级没有请求体的请求，其他请求忽略Upgrade头，继续使用HTTP/1.1
+pub(crate) fn upgrade(req: &HttpRequest, has_body: bool) -> Option<Upgrade> {
+    let headers = req.headers();
+    if has_body
+        || *req.version() != HttpVersion::V1_1
+        || !headers.has_token("upgrade", "h2c")
+        || !headers.has_token("connection", "upgrade")
+        || !headers.has_token("connection", "http2-settings")
+        || headers.get_all("http2-settings").count() != 1 {
+        return None;
+    }
+    let settings = base64url_decode(headers.get("http2-settings")?)?;
+    if !settings.len().is_multiple_of(6) {
+        return None;
+    }
+    let target = match req.query() {
+        "" => req.url().to_string(),
+        query => format!("{}?{}", req.url(), query),
+    };
+    let mut fields = vec![
+        (":method".to_string(), req.method().as_str().to_string()),
+        (":scheme".to_string(), "http".to_string()),
+        (":path".to_string(), target),
+    ];
+    if let Some(host) = headers.host() {
+        fields.push((":authority".to_string(), host.to_string()));
+    }
+    for (name, value) in headers.iter() {
+        let name = name.to_ascii_lowercase();
+        if name == "host" || SKIPPED_HEADERS.contains(&name.as_str())
+            || (name == "te" && !value.eq_ignore_ascii_case("trailers")) {
+            continue;
+        }
+        fields.push((name, value.to_string()));
+    }
+    Some(Upgrade { settings, header_block: hpack_literals(&fields) })
+}
+
+/// 升级后读取客户端的连接序言和第一个SETTINGS帧，生成交给h2的数据：
+/// 序言、合并了HTTP2-Settings的SETTINGS帧、升级请求转换成的HEADERS帧，以及之后已经读到的数据
+pub(crate) async fn upgrade_prefix(stream: &mut impl Stream, buffered: &mut Vec<u8>, upgrade: Upgrade) -> Result<Vec<u8>> {
+    let header_end = PREFACE.len() + 9;
+    fill(stream, buffered, header_end).await?;
+    if !buffered.starts_with(PREFACE) {
+        return Err(Error::bad_request("无效的HTTP/2连接序言"));
+    }
+    let header = &buffered[PREFACE.len()..header_end];
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if header[3] != FRAME_SETTINGS || header[4] & FLAG_ACK != 0 || len > DEFAULT_MAX_FRAME_SIZE || !len.is_multiple_of(6) {
+        return Err(Error::bad_request("HTTP/2连接序言之后应该是SETTINGS帧"));
+    }
+    fill(stream, buffered, header_end + len).await?;
+    // HTTP2-Settings中的设置在前，客户端SETTINGS帧中的同名设置覆盖它们
+    let mut settings = upgrade.settings;
+    settings.extend_from_slice(&buffered[header_end..header_end + len]);
+    let mut prefix = PREFACE.to_vec();
+    prefix.extend(frame(FRAME_SETTINGS, 0, 0, &settings));
+    let mut fragments = upgrade.header_block.chunks(DEFAULT_MAX_FRAME_SIZE).peekable();
+    let mut first = true;
+    while let Some(fragment) = fragments.next() {
+        let end_headers = if fragments.peek().is_none() { FLAG_END_HEADERS } else { 0 };
+        prefix.extend(match first {
+            true => frame(FRAME_HEADERS, FLAG_END_STREAM | end_headers, 1, fragment),
+            false => frame(FRAME_CONTINUATION, end_headers, 1, fragment),
+        });
+        first = false;
+    }
+    prefix.extend_from_slice(&buffered[header_end + len..]);
+    buffered.clear();
+    Ok(prefix)
+}
+
+async fn fill(stream: &mut impl Stream, buffered: &mut Vec<u8>, len: usize) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    while buffered.len() < len {
+        match stream.read(&mut buf).await? {
+            0 => return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "连接已关闭"))),
+            length => buffered.extend_from_slice(&buf[..length]),
+        }
+    }
+    Ok(())
+}
+
+fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(9 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.push(kind);
+    frame.push(flags);
+    frame.extend_from_slice(&(stream_id & 0x7fff_ffff).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// 用不加入索引的字面量编码头字段，不影响HPACK动态表
+fn hpack_literals(fields: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        block.push(0x00);
+        for s in [name, value] {
+            hpack_int(&mut block, 7, s.len());
+            block.extend_from_slice(s.as_bytes());
+        }
+    }
+    block
+}
+
+/// HPACK整数编码，前缀之前的位为0
+fn hpack_int(out: &mut Vec<u8>, prefix_bits: u8, mut value: usize) {
+    let max = (1usize << prefix_bits) - 1;
+    if value < max {
+        out.push(value as u8);
+        return;
+    }
+    out.push(max as u8);
+    value -= max;
+    while value >= 128 {
+        out.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+    out.push(value as u8);
+}
+
+/// 解码HTTP2-Settings使用的base64url，可以没有填充
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    };
+    let s = s.trim().trim_end_matches('=').as_bytes();
+    if s.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.chunks(4) {
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            n |= (value(*c)? as u32) << (18 - 6 * i);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+/// 先读出已经读到的字节，再从内部的流读取
+pub(crate) struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(crate) fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix, pos: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.pos);
+            buf.put_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use http::Request;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use crate::handler::{handler_fn, HelloHandler};
+    use crate::response::{HttpResponse, HttpStatus};
+    use crate::router::Router;
+    use crate::server::{serve_conn, HttpSettings, Protocol, Shutdown};
+    use super::{base64url_decode, frame, hpack_int, PREFACE};
+
+    fn start() -> DuplexStream {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut router = Router::new();
+        router.get("/hello", HelloHandler).unwrap();
+        router.post("/echo", handler_fn(|req, _| Box::pin(async move {
+            let body = req.body().get("__raw").cloned().unwrap_or_default();
+            HttpResponse::new(HttpStatus::Ok, None, Some(body))
+        }))).unwrap();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        tokio::spawn(serve_conn(Arc::new(HttpSettings::new()), Arc::new(router), server, addr, Protocol::Plain, Shutdown::never()));
+        client
+    }
+
+    #[tokio::test]
+    async fn prior_knowledge_multiplexing() {
+        let (client, conn) = h2::client::handshake(start()).await.unwrap();
+        tokio::spawn(conn);
+        let mut client = client.ready().await.unwrap();
+        // 先发出两个请求再读取响应
+        let body = Bytes::from(vec![b'x'; 200_000]);
+        let request = Request::post("http://localhost/echo").body(()).unwrap();
+        let (echo, mut send) = client.send_request(request, false).unwrap();
+        let request = Request::get("http://localhost/hello").body(()).unwrap();
+        let (hello, _) = client.send_request(request, true).unwrap();
+        send.reserve_capacity(body.len());
+        let mut rest = body.clone();
+        while !rest.is_empty() {
+            let capacity = std::future::poll_fn(|cx| send.poll_capacity(cx)).await.unwrap().unwrap();
+            send.send_data(rest.split_to(capacity.min(rest.len())), false).unwrap();
+        }
+        send.send_data(Bytes::new(), true).unwrap();
+
+        let hello = hello.await.unwrap();
+        assert_eq!(hello.status(), 200);
+        assert_eq!(hello.headers()["content-type"], "application/json");
+        assert!(hello.headers().get("connection").is_none());
+        let echo = echo.await.unwrap();
+        assert_eq!(echo.headers()["content-length"], "200000");
+        let mut received = Vec::new();
+        let mut echo_body = echo.into_body();
+        while let Some(data) = echo_body.data().await {
+            let data = data.unwrap();
+            echo_body.flow_control().release_capacity(data.len()).unwrap();
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, body);
+    }
+
+    #[tokio::test]
+    async fn h2c_upgrade_serves_request_as_stream_1() {
+        let mut client = start();
+        client.write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\n\r\n").await.unwrap();
+        let switching = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        let mut response = vec![0u8; switching.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, switching);
+        client.write_all(PREFACE).await.unwrap();
+        client.write_all(&frame(0x4, 0, 0, &[])).await.unwrap();
+        // 读取服务器的帧，直到流1结束
+        let mut data = Vec::new();
+        let mut headers = false;
+        loop {
+            let mut header = [0u8; 9];
+            client.read_exact(&mut header).await.unwrap();
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+            let mut payload = vec![0u8; len];
+            client.read_exact(&mut payload).await.unwrap();
+            match (header[3], stream_id) {
+                (0x1, 1) => headers = true,
+                (0x0, 1) => {
+                    data.extend_from_slice(&payload);
+                    if header[4] & 0x1 != 0 {
+                        break;
+                    }
+                }
+                // SETTINGS需要确认
+                (0x4, 0) if header[4] & 0x1 == 0 => client.write_all(&frame(0x4, 0x1, 0, &[])).await.unwrap(),
+                _ => {}
+            }
+        }
+        assert!(headers);
+        assert_eq!(data, b"{\"code\":200,\"msg\":\"OK\"}");
+    }
+
+    #[tokio::test]
+    async fn requests_with_body_stay_on_http_1_1() {
+        let mut client = start();
+        client.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings, close\r\n\
+Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAAP__\r\nContent-Length: 2\r\n\r\nhi").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nhi"));
+    }
+
+    #[test]
+    fn codecs() {
+        assert_eq!(base64url_decode("AAMAAABkAAQAAP__"), Some(vec![0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 255, 255]));
+        assert_eq!(base64url_decode("AAMAAABkAAQAAP__=="), base64url_decode("AAMAAABkAAQAAP__"));
+        assert_eq!(base64url_decode("a+b/"), None);
+        let mut out = Vec::new();
+        hpack_int(&mut out, 7, 10);
+        hpack_int(&mut out, 5, 1337);
+        assert_eq!(out, [10, 31, 154, 10]);
+        assert_eq!(frame(0x4, 0x1, 0, &[]), [0, 0, 0, 4, 1, 0, 0, 0, 0]);
+    }
+}
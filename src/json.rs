@@ -0,0 +1,145 @@
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::ops::{Deref, DerefMut};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use crate::header;
+use crate::request::HttpRequest;
+use crate::response::{HttpResponse, HttpStatus};
+
+/// 是否是JSON的媒体类型，包括application/json和application/*+json
+pub fn is_json(content_type: &str) -> bool {
+    let media_type = header::media_type(content_type).to_ascii_lowercase();
+    media_type == crate::constant::APPLICATION_JSON
+        || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+/// 解析JSON请求体失败
+#[derive(Debug)]
+pub enum JsonError {
+    /// Content-Type不是JSON，对应415
+    UnsupportedMediaType(Option<String>),
+    /// 请求体不是合法的JSON或与类型不匹配，对应400
+    Invalid(serde_json::Error),
+}
+
+impl JsonError {
+    pub fn status(&self) -> HttpStatus {
+        match self {
+            JsonError::UnsupportedMediaType(_) => HttpStatus::UnsupportedMediaType,
+            JsonError::Invalid(_) => HttpStatus::BadRequest,
+        }
+    }
+    /// 转换为JSON格式的错误响应
+    pub fn to_response(&self) -> HttpResponse {
+        let status = self.status();
+        HttpResponse::json(status.clone(), &json!({ "code": status.code(), "msg": self.to_string() }))
+    }
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            JsonError::UnsupportedMediaType(Some(content_type)) => write!(f, "不支持的Content-Type {}，需要application/json", content_type),
+            JsonError::UnsupportedMediaType(None) => write!(f, "缺少Content-Type，需要application/json"),
+            JsonError::Invalid(err) => write!(f, "无效的JSON: {}", err),
+        }
+    }
+}
+
+impl StdError for JsonError {}
+
+impl From<JsonError> for HttpResponse {
+    fn from(err: JsonError) -> Self {
+        err.to_response()
+    }
+}
+
+/// 按类型解析的JSON请求体
+/// ```ignore
+/// let Json(login) = match Json::<Login>::from_request(req) {
+///     Ok(login) => login,
+///     Err(err) => return err.into(),
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, JsonError> {
+        req.json().map(Json)
+    }
+}
+
+impl<T> Json<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Json<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Json<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::Value;
+    use crate::request::HttpRequest;
+    use crate::response::HttpStatus;
+    use super::{is_json, Json, JsonError};
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Login {
+        user: String,
+        remember: bool,
+    }
+
+    fn request(content_type: &str, body: &str) -> String {
+        format!("POST /login HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type, body.len())
+    }
+
+    #[test]
+    fn media_types() {
+        assert!(is_json("application/json"));
+        assert!(is_json("Application/JSON; charset=utf-8"));
+        assert!(is_json("application/problem+json"));
+        assert!(!is_json("text/json+html"));
+        assert!(!is_json("text/plain"));
+    }
+
+    #[test]
+    fn typed_extractor() {
+        let body = r#"{"user":"张三","remember":true}"#;
+        let raw = request("application/json", body);
+        let req = HttpRequest::from(&raw, body.as_bytes().to_vec(), "127.0.0.1").unwrap();
+        let Json(login) = Json::<Login>::from_request(&req).unwrap();
+        assert_eq!(login, Login { user: "张三".to_string(), remember: true });
+        let value: Value = req.json().unwrap();
+        assert_eq!(value["user"], "张三");
+    }
+
+    #[test]
+    fn rejects_bad_bodies() {
+        let raw = request("text/plain", "{}");
+        let req = HttpRequest::from(&raw, b"{}".to_vec(), "127.0.0.1").unwrap();
+        let err = req.json::<Value>().unwrap_err();
+        assert!(matches!(err, JsonError::UnsupportedMediaType(Some(_))));
+        assert_eq!(err.to_response().status(), &HttpStatus::UnsupportedMediaType);
+
+        let raw = request("application/json", r#"{"user":1}"#);
+        let req = HttpRequest::from(&raw, br#"{"user":1}"#.to_vec(), "127.0.0.1").unwrap();
+        let err = Json::<Login>::from_request(&req).unwrap_err();
+        assert_eq!(err.status(), HttpStatus::BadRequest);
+        assert!(req.json::<Value>().is_ok());
+    }
+}
@@ -0,0 +1,38 @@
+// 服务器模块
+pub mod server;
+// HTTPS模块
+pub mod tls;
+// HTTP/2模块
+mod http2;
+// 请求模块
+pub mod request;
+// 请求头解析模块
+pub mod parser;
+// 参数模块
+pub mod params;
+// multipart表单模块
+pub mod multipart;
+// 响应模块
+pub mod response;
+// JSON模块
+pub mod json;
+// http头模块
+pub mod header;
+// cookie模块
+pub mod cookie;
+// 路由模块
+pub mod router;
+// 处理器模块
+pub mod handler;
+// 中间件模块
+pub mod middleware;
+// 会话模块
+pub mod session;
+// 应用状态模块
+pub mod state;
+// 错误处理模块
+pub mod error;
+// 工具模块
+pub mod utils;
+// 常量
+pub mod constant;
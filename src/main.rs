@@ -1,25 +1,16 @@
-// 服务器模块
-mod server;
-// 请求模块
-mod request;
-// 响应模块
-mod response;
-// 路由模块
-mod router;
-// 处理器模块
-mod handler;
-// 错误处理模块
-mod error;
-// 工具模块
-mod utils;
-// 常量
-mod constant;
-
-use crate::server::{HttpSettings, Server};
+use my_http_server::handler::{HelloHandler, StaticHandler};
+use my_http_server::router::Router;
+use my_http_server::server::{shutdown_signal, HttpSettings, Server};
 
 #[tokio::main]
 async fn main() {
     let http_settings = HttpSettings::new();
-    let server = Server::new("127.0.0.1:8080", http_settings);
-    server.run().await.unwrap();
+    let mut router = Router::new();
+    router
+        .get("/hello", HelloHandler).unwrap()
+        // 静态资源，相对于运行时的工作目录
+        .get("/*path", StaticHandler::new("static")).unwrap();
+    let server = Server::new("127.0.0.1:8080", http_settings, router);
+    // Ctrl-C或SIGTERM时等待进行中的请求完成后退出
+    server.run_until(shutdown_signal()).await.unwrap();
 }
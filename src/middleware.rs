@@ -0,0 +1,180 @@
+use crate::handler::{BoxFuture, Handler};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Router;
+use crate::state::State;
+
+/// 中间件接口
+/// 可以在调用`next.run(req)`之前检查或修改请求，直接返回响应以中断后续处理，
+/// 或者在之后处理返回的响应
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(&'a self, req: HttpRequest<'a>, state: &'a State, next: Next<'a>) -> BoxFuture<'a, HttpResponse>;
+}
+
+/// 闭包中间件，见[middleware_fn]
+pub struct FnMiddleware<F>(F);
+
+impl<F> Middleware for FnMiddleware<F>
+    where F: for<'a> Fn(HttpRequest<'a>, &'a State, Next<'a>) -> BoxFuture<'a, HttpResponse> + Send + Sync {
+    fn handle<'a>(&'a self, req: HttpRequest<'a>, state: &'a State, next: Next<'a>) -> BoxFuture<'a, HttpResponse> {
+        (self.0)(req, state, next)
+    }
+}
+
+/// 将闭包转换为中间件，闭包返回`Box::pin(async move { ... })`
+pub fn middleware_fn<F>(f: F) -> FnMiddleware<F>
+    where F: for<'a> Fn(HttpRequest<'a>, &'a State, Next<'a>) -> BoxFuture<'a, HttpResponse> + Send + Sync {
+    FnMiddleware(f)
+}
+
+/// 中间件链的最内层
+#[derive(Clone, Copy)]
+pub(crate) enum Endpoint<'a> {
+    /// 全局中间件之后进行路由匹配
+    Router(&'a Router),
+    /// 路由中间件之后调用处理器
+    Handler(&'a dyn Handler),
+}
+
+/// 剩余的中间件链
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    endpoint: Endpoint<'a>,
+    state: &'a State,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middlewares: &'a [Box<dyn Middleware>], endpoint: Endpoint<'a>, state: &'a State) -> Self {
+        Self { middlewares, endpoint, state }
+    }
+    /// 调用下一个中间件，没有中间件时调用最内层
+    pub fn run(self, req: HttpRequest<'a>) -> BoxFuture<'a, HttpResponse> {
+        Box::pin(async move {
+            match self.middlewares.split_first() {
+                Some((first, rest)) => {
+                    let next = Next { middlewares: rest, ..self };
+                    first.handle(req, self.state, next).await
+                }
+                None => match self.endpoint {
+                    Endpoint::Router(router) => router.dispatch(req).await,
+                    Endpoint::Handler(handler) => handler.handle(&req, self.state).await,
+                },
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::handler::handler_fn;
+    use crate::request::{HttpMethod, HttpRequest};
+    use crate::response::{HttpResponse, HttpStatus};
+    use crate::router::Router;
+    use super::{middleware_fn, Middleware};
+
+    /// 记录中间件执行顺序
+    #[derive(Default)]
+    struct Trace(Mutex<Vec<String>>);
+
+    impl Trace {
+        fn push(&self, entry: impl Into<String>) {
+            self.0.lock().unwrap().push(entry.into());
+        }
+        fn entries(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn tracing(name: &'static str) -> impl Middleware {
+        middleware_fn(move |req, state, next| Box::pin(async move {
+            let trace = state.get::<Arc<Trace>>().unwrap();
+            trace.push(format!("{} before", name));
+            let response = next.run(req).await;
+            trace.push(format!("{} after", name));
+            response
+        }))
+    }
+
+    fn router() -> (Router, Arc<Trace>) {
+        let trace = Arc::new(Trace::default());
+        let mut router = Router::new();
+        router.insert_state(trace.clone());
+        router.get("/hello", handler_fn(|_, state| Box::pin(async move {
+            state.get::<Arc<Trace>>().unwrap().push("handler");
+            HttpResponse::new(HttpStatus::Ok, None, Some(b"hello".to_vec()))
+        }))).unwrap();
+        (router, trace)
+    }
+
+    async fn get(router: &Router, url: &str) -> HttpResponse {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", url);
+        let req = HttpRequest::from(&raw, Vec::new(), "127.0.0.1").unwrap();
+        router.route(req).await
+    }
+
+    #[tokio::test]
+    async fn runs_global_then_route_middlewares_in_order() {
+        let (mut router, log) = router();
+        router.layer(HttpMethod::Get, "/hello", tracing("c")).unwrap();
+        router.wrap(tracing("a")).wrap(tracing("b"));
+        get(&router, "/hello").await;
+        assert_eq!(log.entries(), ["a before", "b before", "c before", "handler", "c after", "b after", "a after"]);
+    }
+
+    #[tokio::test]
+    async fn global_middlewares_see_unmatched_requests() {
+        let (mut router, log) = router();
+        router.wrap(tracing("a"));
+        router.layer(HttpMethod::Get, "/hello", tracing("c")).unwrap();
+        let response = get(&router, "/missing").await;
+        assert_eq!(*response.status(), HttpStatus::NotFound);
+        assert_eq!(log.entries(), ["a before", "a after"]);
+    }
+
+    #[tokio::test]
+    async fn can_short_circuit_and_post_process() {
+        let (mut router, log) = router();
+        router.wrap(middleware_fn(|req, _, next| Box::pin(async move {
+            let mut response = next.run(req).await;
+            response.set_header("X-Powered-By", "middleware");
+            response
+        })));
+        router.layer(HttpMethod::Get, "/hello", middleware_fn(|req, _, next| Box::pin(async move {
+            if req.search_params().contains_key("deny") {
+                return HttpResponse::new(HttpStatus::BadRequest, None, None);
+            }
+            next.run(req).await
+        }))).unwrap();
+        let response = get(&router, "/hello?deny").await;
+        assert_eq!(*response.status(), HttpStatus::BadRequest);
+        assert_eq!(response.header("X-Powered-By"), Some("middleware"));
+        assert!(log.entries().is_empty());
+        let response = get(&router, "/hello").await;
+        assert_eq!(*response.status(), HttpStatus::Ok);
+        assert_eq!(log.entries(), ["handler"]);
+    }
+
+    #[tokio::test]
+    async fn can_pass_data_to_handlers() {
+        struct User(&'static str);
+        let mut router = Router::new();
+        router.wrap(middleware_fn(|mut req, _, next| Box::pin(async move {
+            req.extensions_mut().insert(User("alice"));
+            next.run(req).await
+        })));
+        router.get("/me", handler_fn(|req, _| Box::pin(async move {
+            let user = req.extensions().get::<User>().unwrap();
+            HttpResponse::new(HttpStatus::Ok, None, Some(user.0.as_bytes().to_vec()))
+        }))).unwrap();
+        let response = get(&router, "/me").await;
+        assert_eq!(response.body().len(), Some(5));
+    }
+
+    #[test]
+    fn layer_requires_registered_route() {
+        let (mut router, _) = router();
+        assert!(router.layer(HttpMethod::Post, "/hello", tracing("a")).is_err());
+        assert!(router.layer(HttpMethod::Get, "/missing", tracing("a")).is_err());
+    }
+}
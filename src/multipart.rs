@@ -0,0 +1,489 @@
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::error::{Error, Result};
+use crate::header::{self, HeaderMap};
+use crate::utils::scan;
+
+/// multipart表单的大小限制
+#[derive(Clone, Debug)]
+pub struct MultipartLimits {
+    /// 单个部分的最大大小
+    pub max_part_size: usize,
+    /// 整个请求体的最大大小
+    pub max_total_size: usize,
+    /// 单个部分超过这个大小时写入临时文件
+    pub memory_limit: usize,
+    /// 每个部分头的最大大小
+    pub max_header_size: usize,
+}
+
+impl Default for MultipartLimits {
+    fn default() -> Self {
+        Self {
+            max_part_size: 32 * 1024 * 1024, // 32mb
+            max_total_size: 64 * 1024 * 1024, // 64mb
+            memory_limit: 256 * 1024, // 256kb
+            max_header_size: 8192,
+        }
+    }
+}
+
+/// 表单中的一个部分
+#[derive(Debug)]
+pub struct Part {
+    headers: HeaderMap,
+    name: String,
+    filename: Option<String>,
+    data: PartData,
+}
+
+/// 部分的内容，较小的保存在内存中，较大的保存在临时文件中
+#[derive(Debug)]
+pub enum PartData {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+impl Part {
+    /// 部分的头，如Content-Disposition、Content-Type
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    /// 表单字段名
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// 上传文件的文件名，普通字段为None
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+    /// 部分的Content-Type，没有时为None，按RFC 7578应视为text/plain
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.content_type()
+    }
+    pub fn data(&self) -> &PartData {
+        &self.data
+    }
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            PartData::Memory(bytes) => bytes.len() as u64,
+            PartData::File(file) => file.len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// 内存中的内容，已写入临时文件时为None
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match &self.data {
+            PartData::Memory(bytes) => Some(bytes),
+            PartData::File(_) => None,
+        }
+    }
+    /// 临时文件的路径，内容在内存中时为None
+    pub fn path(&self) -> Option<&Path> {
+        match &self.data {
+            PartData::Memory(_) => None,
+            PartData::File(file) => Some(&file.path),
+        }
+    }
+    /// 读取全部内容
+    pub fn read_all(&self) -> io::Result<Vec<u8>> {
+        match &self.data {
+            PartData::Memory(bytes) => Ok(bytes.clone()),
+            PartData::File(file) => fs::read(&file.path),
+        }
+    }
+    /// 把内容保存到指定路径，临时文件会被移动过去
+    pub fn save_to(self, path: impl AsRef<Path>) -> io::Result<()> {
+        match self.data {
+            PartData::Memory(bytes) => fs::write(path, bytes),
+            PartData::File(file) => file.persist(path),
+        }
+    }
+}
+
+static TEMP_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// 临时文件，drop时删除
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl TempFile {
+    fn create() -> io::Result<(Self, File)> {
+        let id = TEMP_FILE_ID.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir()
+            .join(format!("my-http-server-{}-{}.part", std::process::id(), id));
+        let file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        Ok((Self { path, len: 0 }, file))
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+    /// 移动到指定路径，跨文件系统时复制
+    pub fn persist(self, to: impl AsRef<Path>) -> io::Result<()> {
+        if fs::rename(&self.path, to.as_ref()).is_err() {
+            fs::copy(&self.path, to.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// 正在写入的部分
+struct PartWriter {
+    headers: HeaderMap,
+    name: String,
+    filename: Option<String>,
+    memory: Vec<u8>,
+    file: Option<(TempFile, File)>,
+}
+
+impl PartWriter {
+    fn size(&self) -> u64 {
+        match &self.file {
+            Some((temp, _)) => temp.len,
+            None => self.memory.len() as u64,
+        }
+    }
+    fn write(&mut self, data: &[u8], limits: &MultipartLimits) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.size() + data.len() as u64 > limits.max_part_size as u64 {
+            return Err(Error::too_large(format!("表单字段 {} 大小超出限制", self.name)));
+        }
+        // 超出内存限制时，已有内容转移到临时文件
+        if self.file.is_none() && self.memory.len() + data.len() > limits.memory_limit {
+            let (mut temp, mut file) = TempFile::create()?;
+            file.write_all(&self.memory)?;
+            temp.len = self.memory.len() as u64;
+            self.memory = Vec::new();
+            self.file = Some((temp, file));
+        }
+        match &mut self.file {
+            Some((temp, file)) => {
+                file.write_all(data)?;
+                temp.len += data.len() as u64;
+            }
+            None => self.memory.extend_from_slice(data),
+        }
+        Ok(())
+    }
+    fn finish(self) -> Result<Part> {
+        let data = match self.file {
+            Some((temp, mut file)) => {
+                file.flush()?;
+                PartData::File(temp)
+            }
+            None => PartData::Memory(self.memory),
+        };
+        Ok(Part { headers: self.headers, name: self.name, filename: self.filename, data })
+    }
+}
+
+enum ParseState {
+    // 第一个分隔符之前的内容
+    Preamble,
+    // 分隔符之后，判断是结束还是下一个部分
+    Boundary,
+    Headers,
+    Body(Box<PartWriter>),
+    Done,
+}
+
+/// 增量的multipart/form-data解析器，数据可以在任意位置分块传入
+pub struct MultipartParser {
+    // CRLF--boundary
+    delimiter: Vec<u8>,
+    limits: MultipartLimits,
+    state: ParseState,
+    buffered: Vec<u8>,
+    total: usize,
+    parts: Vec<Part>,
+}
+
+impl MultipartParser {
+    pub fn new(boundary: &str, limits: MultipartLimits) -> Result<Self> {
+        // RFC 2046 boundary长度为1到70
+        if boundary.is_empty() || boundary.len() > 70 {
+            return Err(Error::bad_request("没有有效的boundary"));
+        }
+        Ok(Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            limits,
+            state: ParseState::Preamble,
+            // 第一个分隔符前面可以没有CRLF，补上之后统一处理
+            buffered: b"\r\n".to_vec(),
+            total: 0,
+            parts: Vec::new(),
+        })
+    }
+    /// 根据Content-Type创建解析器，不是multipart/form-data时返回None
+    pub fn from_content_type(content_type: &str, limits: MultipartLimits) -> Result<Option<Self>> {
+        if !header::media_type(content_type).eq_ignore_ascii_case(crate::constant::MULTIPART_FORM_DATA) {
+            return Ok(None);
+        }
+        let boundary = header::param(content_type, "boundary")
+            .ok_or_else(|| Error::bad_request("没有有效的boundary"))?;
+        Self::new(&boundary, limits).map(Some)
+    }
+
+    /// 传入下一段数据
+    pub fn feed(&mut self, data: &[u8]) -> Result<()> {
+        self.total += data.len();
+        if self.total > self.limits.max_total_size {
+            return Err(Error::too_large("表单大小超出限制"));
+        }
+        self.buffered.extend_from_slice(data);
+        self.parse()
+    }
+
+    /// 再传入`len`字节时是否可能写入临时文件
+    /// 新的部分不会超过已缓冲的数据加上传入的数据，按这个上限估计
+    pub(crate) fn may_spill(&self, len: usize) -> bool {
+        let memory = match &self.state {
+            ParseState::Body(writer) if writer.file.is_some() => return true,
+            ParseState::Body(writer) => writer.memory.len(),
+            _ => 0,
+        };
+        memory + self.buffered.len() + len > self.limits.memory_limit
+    }
+
+    /// 数据传入完毕，返回所有部分
+    pub fn finish(self) -> Result<Vec<Part>> {
+        match self.state {
+            ParseState::Done => Ok(self.parts),
+            _ => Err(Error::bad_request("表单内容不完整")),
+        }
+    }
+
+    fn parse(&mut self) -> Result<()> {
+        loop {
+            match &mut self.state {
+                ParseState::Preamble => {
+                    match scan(&self.buffered, &self.delimiter) {
+                        Some(pos) => {
+                            self.buffered.drain(..pos + self.delimiter.len());
+                            self.state = ParseState::Boundary;
+                        }
+                        None => {
+                            // 只保留可能是分隔符开头的部分
+                            let keep = self.delimiter.len() - 1;
+                            if self.buffered.len() > keep {
+                                self.buffered.drain(..self.buffered.len() - keep);
+                            }
+                            return Ok(());
+                        }
+                    }
+                }
+                ParseState::Boundary => {
+                    // 分隔符之后可以有空白
+                    let start = self.buffered.iter()
+                        .position(|b| *b != b' ' && *b != b'\t')
+                        .unwrap_or(self.buffered.len());
+                    let rest = &self.buffered[start..];
+                    if rest.len() < 2 {
+                        if self.buffered.len() > self.limits.max_header_size {
+                            return Err(Error::bad_request("表单内容损坏"));
+                        }
+                        return Ok(());
+                    }
+                    if rest.starts_with(b"--") {
+                        // 结束分隔符之后的内容忽略
+                        self.buffered = Vec::new();
+                        self.state = ParseState::Done;
+                    } else if rest.starts_with(b"\r\n") {
+                        self.buffered.drain(..start + 2);
+                        self.state = ParseState::Headers;
+                    } else {
+                        return Err(Error::bad_request("表单内容损坏"));
+                    }
+                }
+                ParseState::Headers => {
+                    // 没有头的部分直接以空行开始
+                    let end = if self.buffered.starts_with(b"\r\n") {
+                        Some(0)
+                    } else {
+                        scan(&self.buffered, b"\r\n\r\n").map(|pos| pos + 2)
+                    };
+                    let Some(end) = end else {
+                        if self.buffered.len() > self.limits.max_header_size {
+                            return Err(Error::too_large("表单字段头大小超出限制"));
+                        }
+                        return Ok(());
+                    };
+                    if end > self.limits.max_header_size {
+                        return Err(Error::too_large("表单字段头大小超出限制"));
+                    }
+                    let writer = parse_part_headers(&self.buffered[..end])?;
+                    self.buffered.drain(..end + 2);
+                    self.state = ParseState::Body(Box::new(writer));
+                }
+                ParseState::Body(writer) => {
+                    match scan(&self.buffered, &self.delimiter) {
+                        Some(pos) => {
+                            writer.write(&self.buffered[..pos], &self.limits)?;
+                            self.buffered.drain(..pos + self.delimiter.len());
+                            let ParseState::Body(writer) = std::mem::replace(&mut self.state, ParseState::Boundary) else {
+                                unreachable!()
+                            };
+                            self.parts.push(writer.finish()?);
+                        }
+                        None => {
+                            // 末尾可能是分隔符的开头，留到下次判断
+                            let keep = self.delimiter.len() - 1;
+                            if self.buffered.len() > keep {
+                                let end = self.buffered.len() - keep;
+                                writer.write(&self.buffered[..end], &self.limits)?;
+                                self.buffered.drain(..end);
+                            }
+                            return Ok(());
+                        }
+                    }
+                }
+                ParseState::Done => {
+                    self.buffered.clear();
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// 解析部分的头，每行以CRLF结束
+fn parse_part_headers(raw: &[u8]) -> Result<PartWriter> {
+    let raw = String::from_utf8_lossy(raw);
+    let mut headers = HeaderMap::new();
+    for line in raw.split("\r\n").filter(|l| !l.is_empty()) {
+        let (key, value) = line.split_once(':')
+            .ok_or_else(|| Error::bad_request("表单字段头损坏"))?;
+        headers.try_append(key.trim(), value.trim())
+            .map_err(|_| Error::bad_request("表单字段头损坏"))?;
+    }
+    let disposition = headers.get("content-disposition")
+        .ok_or_else(|| Error::bad_request("表单内容没有Content-Disposition"))?;
+    if !header::media_type(disposition).eq_ignore_ascii_case("form-data") {
+        return Err(Error::bad_request("表单内容不是form-data"));
+    }
+    let name = header::param(disposition, "name")
+        .ok_or_else(|| Error::bad_request("表单内容没有name属性"))?
+        .into_owned();
+    let filename = header::param(disposition, "filename").map(Cow::into_owned);
+    Ok(PartWriter { headers, name, filename, memory: Vec::new(), file: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MultipartLimits, MultipartParser, ParseState, Part};
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"Title\"\r\n\r\n\
+first line\r\nsecond line\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a;b \\\"c\\\".bin\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\
+\x00\x01\r\n--Xy\xff\r\n\
+--XyZ--\r\nepilogue";
+
+    fn parse(body: &[u8], limits: MultipartLimits, step: usize) -> crate::error::Result<Vec<Part>> {
+        let mut parser = MultipartParser::new("XyZ", limits)?;
+        for chunk in body.chunks(step) {
+            parser.feed(chunk)?;
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn parses_fields_and_files_at_any_split() {
+        for step in 1..BODY.len() {
+            let parts = parse(BODY, MultipartLimits::default(), step).unwrap();
+            assert_eq!(parts.len(), 2);
+            assert_eq!(parts[0].name(), "Title");
+            assert_eq!(parts[0].filename(), None);
+            assert_eq!(parts[0].content_type(), None);
+            assert_eq!(parts[0].bytes(), Some(&b"first line\r\nsecond line"[..]));
+            assert_eq!(parts[1].name(), "file");
+            assert_eq!(parts[1].filename(), Some("a;b \"c\".bin"));
+            assert_eq!(parts[1].content_type(), Some("application/octet-stream"));
+            assert_eq!(parts[1].bytes(), Some(&b"\x00\x01\r\n--Xy\xff"[..]));
+        }
+    }
+
+    #[test]
+    fn spills_large_parts_to_temp_file() {
+        let limits = MultipartLimits { memory_limit: 4, ..Default::default() };
+        let parts = parse(BODY, limits, 3).unwrap();
+        assert_eq!(parts[0].bytes(), None);
+        let path = parts[0].path().unwrap().to_path_buf();
+        assert_eq!(parts[0].read_all().unwrap(), b"first line\r\nsecond line");
+        assert_eq!(parts[1].len(), 9);
+        drop(parts);
+        assert!(!path.exists());
+    }
+
+    /// 已经写入临时文件的部分数
+    fn files(parser: &MultipartParser) -> usize {
+        let writing = matches!(&parser.state, ParseState::Body(writer) if writer.file.is_some());
+        parser.parts.iter().filter(|p| p.path().is_some()).count() + writing as usize
+    }
+
+    #[test]
+    fn predicts_spills() {
+        for memory_limit in [4, 8, 16] {
+            for step in 1..BODY.len() {
+                let limits = MultipartLimits { memory_limit, ..Default::default() };
+                let mut parser = MultipartParser::new("XyZ", limits).unwrap();
+                for chunk in BODY.chunks(step) {
+                    let may_spill = parser.may_spill(chunk.len());
+                    let before = files(&parser);
+                    parser.feed(chunk).unwrap();
+                    assert!(may_spill || files(&parser) == before, "{} {}", memory_limit, step);
+                }
+                assert!(files(&parser) > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = MultipartLimits { max_part_size: 10, ..Default::default() };
+        assert!(parse(BODY, limits, 7).is_err());
+        let limits = MultipartLimits { max_total_size: 50, ..Default::default() };
+        assert!(parse(BODY, limits, 7).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        // 缺少结束分隔符
+        assert!(parse(&BODY[..BODY.len() - 12], MultipartLimits::default(), 5).is_err());
+        // 缺少name
+        let body = b"--XyZ\r\nContent-Disposition: form-data\r\n\r\nx\r\n--XyZ--";
+        assert!(parse(body, MultipartLimits::default(), 5).is_err());
+        assert!(MultipartParser::new("", MultipartLimits::default()).is_err());
+        // 非法的头名称是客户端的错误
+        let body = b"--XyZ\r\nContent Disposition: form-data; name=\"a\"\r\n\r\nx\r\n--XyZ--";
+        let err = parse(body, MultipartLimits::default(), 5).unwrap_err();
+        assert_eq!(err.status(), crate::response::HttpStatus::BadRequest);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::utils::percent_decode;
+
+/// 查询参数或urlencoded表单，保留顺序和大小写，同名的参数可以有多个值
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 按照WHATWG的application/x-www-form-urlencoded规则解析
+    /// 空的部分被忽略，+ 解码为空格，不合法的百分号编码保持原样
+    pub fn parse(raw: impl AsRef<[u8]>) -> Self {
+        let mut params = Self::new();
+        for part in raw.as_ref().split(|&b| b == b'&') {
+            if part.is_empty() {
+                continue;
+            }
+            let (key, value) = match part.iter().position(|&b| b == b'=') {
+                Some(pos) => (&part[..pos], &part[pos + 1..]),
+                None => (part, &b""[..]),
+            };
+            params.append(decode(key), decode(value));
+        }
+        params
+    }
+
+    /// 获取第一个值
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+    /// 获取所有值，如 ?tag=a&tag=b
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item=&'a str> + 'a {
+        self.entries.iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|(k, _)| k == key)
+    }
+    pub fn append(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((key.into(), value.into()));
+    }
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// 解码一个名称或值
+fn decode(raw: &[u8]) -> String {
+    let replaced: Vec<u8> = raw.iter()
+        .map(|&b| if b == b'+' { b' ' } else { b })
+        .collect();
+    String::from_utf8_lossy(&percent_decode(&replaced)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Params;
+
+    fn pairs(raw: &str) -> Vec<(String, String)> {
+        Params::parse(raw).iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_percent_and_plus() {
+        let params = Params::parse("q=hello+world%21&%E4%BD%A0=%E5%A5%BD&path=a%2Fb");
+        assert_eq!(params.get("q"), Some("hello world!"));
+        assert_eq!(params.get("你"), Some("好"));
+        assert_eq!(params.get("path"), Some("a/b"));
+    }
+
+    #[test]
+    fn keeps_repeated_keys_and_case() {
+        let params = Params::parse("tag=a&Tag=B&tag=c");
+        assert_eq!(params.get_all("tag").collect::<Vec<_>>(), ["a", "c"]);
+        assert_eq!(params.get("Tag"), Some("B"));
+        assert_eq!(params.get("TAG"), None);
+    }
+
+    #[test]
+    fn edge_cases() {
+        assert!(Params::parse("").is_empty());
+        assert!(Params::parse("&&").is_empty());
+        assert_eq!(pairs("a"), [("a".to_string(), String::new())]);
+        assert_eq!(pairs("=b"), [(String::new(), "b".to_string())]);
+        assert_eq!(pairs("a=b=c"), [("a".to_string(), "b=c".to_string())]);
+        // 不合法的编码保持原样
+        assert_eq!(pairs("bad=%zz%4"), [("bad".to_string(), "%zz%4".to_string())]);
+        assert_eq!(pairs("x=%FF"), [("x".to_string(), "\u{FFFD}".to_string())]);
+    }
+}
@@ -0,0 +1,655 @@
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::ops::Range;
+use crate::utils::is_tchar;
+
+/// 请求头解析错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// 请求方法不是token
+    InvalidMethod,
+    /// 请求目标为空或包含非法字符
+    InvalidTarget,
+    /// 版本不是 HTTP/x.y，或请求行没有正确结束
+    InvalidVersion,
+    /// 主版本不是1，HTTP/2不使用文本格式的请求行
+    UnsupportedVersion,
+    /// 头名称不是token，或行首是空白
+    InvalidHeaderName,
+    /// 头的值包含控制字符，或行没有正确结束
+    InvalidHeaderValue,
+    /// 超过最大请求头大小
+    TooLarge,
+    /// 严格模式下行只以LF结束
+    BareLf,
+    /// CR后面不是LF
+    BareCr,
+    /// 以空白开始的续行
+    ObsFold,
+    /// 头名称和冒号之间有空白
+    WhitespaceBeforeColon,
+    /// 头没有冒号
+    MissingColon,
+    /// HTTP/1.1请求没有Host
+    MissingHost,
+    /// 有多个Host
+    MultipleHost,
+    /// Content-Length不是数字或超出范围
+    InvalidContentLength,
+    /// 严格模式下有多个相同的Content-Length
+    DuplicateContentLength,
+    /// 多个Content-Length的值不同
+    ConflictingContentLength,
+    /// 同时有Transfer-Encoding和Content-Length
+    ContentLengthWithTransferEncoding,
+    /// 最后一个编码不是chunked
+    UnsupportedTransferEncoding,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            ParseError::InvalidMethod => "无法解析请求方法",
+            ParseError::InvalidTarget => "无法解析请求地址",
+            ParseError::InvalidVersion => "无法解析http协议版本",
+            ParseError::UnsupportedVersion => "不支持的http协议版本",
+            ParseError::InvalidHeaderName => "非法的请求头名称",
+            ParseError::InvalidHeaderValue => "非法的请求头的值",
+            ParseError::TooLarge => "请求头大小超出限制",
+            ParseError::BareLf => "行必须以CRLF结束",
+            ParseError::BareCr => "CR后面必须是LF",
+            ParseError::ObsFold => "不支持多行的头(obs-fold)",
+            ParseError::WhitespaceBeforeColon => "头名称和冒号之间不能有空白",
+            ParseError::MissingColon => "请求头缺少冒号",
+            ParseError::MissingHost => "HTTP/1.1请求缺少Host",
+            ParseError::MultipleHost => "Host只能有一个",
+            ParseError::InvalidContentLength => "无效的Content-Length",
+            ParseError::DuplicateContentLength => "重复的Content-Length",
+            ParseError::ConflictingContentLength => "Content-Length的值不一致",
+            ParseError::ContentLengthWithTransferEncoding => "Transfer-Encoding和Content-Length不能同时存在",
+            ParseError::UnsupportedTransferEncoding => "不支持的Transfer-Encoding",
+        })
+    }
+}
+
+impl StdError for ParseError {}
+
+/// 解析状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// 请求行之前的空行
+    Start,
+    /// 空行的CR之后
+    StartLf,
+    Method,
+    Target,
+    Version,
+    /// 请求行的CR之后
+    RequestLineLf,
+    /// 行首，下一个头或结束的空行
+    LineStart,
+    Name,
+    /// 冒号之后的空白
+    ValueStart,
+    Value,
+    /// 头的CR之后
+    HeaderLf,
+    /// 结束空行的CR之后
+    EndLf,
+}
+
+/// 增量的请求头解析器，数据可以在任意位置分开到达
+/// 每次调用从上次停下的位置继续，已经检查过的字节不会重复扫描
+/// 默认是严格模式，行必须以CRLF结束，不严格时也接受只有LF的行
+/// ```ignore
+/// let mut parser = HeadParser::new(8192);
+/// loop {
+///     if let Some(head) = parser.parse(&buffered)? {
+///         break head;
+///     }
+///     // 继续读取，追加到buffered
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HeadParser {
+    state: State,
+    // 下一个要检查的字节
+    pos: usize,
+    // 当前部分开始的位置
+    start: usize,
+    // 值去掉末尾空白后的结束位置
+    value_end: usize,
+    max_size: usize,
+    strict: bool,
+    method: Range<usize>,
+    target: Range<usize>,
+    version: Range<usize>,
+    name: Range<usize>,
+    headers: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl HeadParser {
+    /// 请求头超过`max_size`字节时返回[ParseError::TooLarge]
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            state: State::Start,
+            pos: 0,
+            start: 0,
+            value_end: 0,
+            max_size,
+            strict: true,
+            method: 0..0,
+            target: 0..0,
+            version: 0..0,
+            name: 0..0,
+            headers: Vec::new(),
+        }
+    }
+
+    /// 是否使用严格模式
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// 继续解析，`buf`是从请求开始的全部数据，每次调用只能在末尾追加
+    /// 请求头完整时返回解析结果，解析器重置，可以用于下一个请求
+    /// 出错后不能继续使用
+    pub fn parse(&mut self, buf: &[u8]) -> Result<Option<Head>, ParseError> {
+        debug_assert!(buf.len() >= self.pos);
+        let end = buf.len().min(self.max_size);
+        while self.pos < end {
+            // 连续的普通字符一次跳过，只有分隔符需要按状态处理
+            match self.state {
+                State::Method | State::Name => {
+                    self.skip(buf, end, is_tchar);
+                }
+                State::Target => {
+                    self.skip(buf, end, |b| matches!(b, 0x21..=0x7E));
+                }
+                State::Value => {
+                    let start = self.pos;
+                    self.skip(buf, end, is_field_vchar);
+                    if self.pos > start {
+                        self.value_end = self.pos;
+                    }
+                }
+                _ => {}
+            }
+            if self.pos == end {
+                break;
+            }
+            let pos = self.pos;
+            let b = buf[pos];
+            self.pos += 1;
+            match self.state {
+                State::Start => match b {
+                    b'\r' => self.state = State::StartLf,
+                    b'\n' => self.bare_lf()?,
+                    _ if is_tchar(b) => self.begin(State::Method, pos),
+                    _ => return Err(ParseError::InvalidMethod),
+                },
+                State::StartLf => match b {
+                    b'\n' => self.state = State::Start,
+                    _ => return Err(ParseError::BareCr),
+                },
+                State::Method => match b {
+                    b' ' => {
+                        self.method = self.start..pos;
+                        self.begin(State::Target, pos + 1);
+                    }
+                    _ if is_tchar(b) => {}
+                    _ => return Err(ParseError::InvalidMethod),
+                },
+                State::Target => match b {
+                    b' ' if pos > self.start => {
+                        self.target = self.start..pos;
+                        self.begin(State::Version, pos + 1);
+                    }
+                    0x21..=0x7E => {}
+                    // 缺少版本
+                    b'\r' | b'\n' if pos > self.start => return Err(ParseError::InvalidVersion),
+                    _ => return Err(ParseError::InvalidTarget),
+                },
+                State::Version => match b {
+                    b'\r' | b'\n' => {
+                        if !is_version(&buf[self.start..pos]) {
+                            return Err(ParseError::InvalidVersion);
+                        }
+                        // RFC 9110 2.5 只支持HTTP/1.x，其他主版本返回505
+                        if buf[self.start + 5] != b'1' {
+                            return Err(ParseError::UnsupportedVersion);
+                        }
+                        self.version = self.start..pos;
+                        self.state = if b == b'\r' { State::RequestLineLf } else { State::LineStart };
+                        if b == b'\n' {
+                            self.bare_lf()?;
+                        }
+                    }
+                    _ if pos - self.start < 8 => {}
+                    _ => return Err(ParseError::InvalidVersion),
+                },
+                State::RequestLineLf => match b {
+                    b'\n' => self.state = State::LineStart,
+                    _ => return Err(ParseError::BareCr),
+                },
+                State::LineStart => match b {
+                    b'\r' => self.state = State::EndLf,
+                    b'\n' => {
+                        self.bare_lf()?;
+                        return Ok(Some(self.finish()));
+                    }
+                    _ if is_tchar(b) => self.begin(State::Name, pos),
+                    // RFC 9112 5.2 不接受续行，可能被用来隐藏头
+                    b' ' | b'\t' => return Err(ParseError::ObsFold),
+                    _ => return Err(ParseError::InvalidHeaderName),
+                },
+                State::Name => match b {
+                    b':' => {
+                        self.name = self.start..pos;
+                        self.begin(State::ValueStart, pos + 1);
+                        self.value_end = pos + 1;
+                    }
+                    _ if is_tchar(b) => {}
+                    // RFC 9112 5.1 名称和冒号之间有空白必须拒绝
+                    b' ' | b'\t' => return Err(ParseError::WhitespaceBeforeColon),
+                    b'\r' | b'\n' => return Err(ParseError::MissingColon),
+                    _ => return Err(ParseError::InvalidHeaderName),
+                },
+                State::ValueStart => match b {
+                    b' ' | b'\t' => {
+                        self.start = pos + 1;
+                        self.value_end = pos + 1;
+                    }
+                    b'\r' => self.state = State::HeaderLf,
+                    b'\n' => {
+                        self.bare_lf()?;
+                        self.end_header();
+                    }
+                    _ if is_field_vchar(b) => {
+                        self.value_end = pos + 1;
+                        self.state = State::Value;
+                    }
+                    _ => return Err(ParseError::InvalidHeaderValue),
+                },
+                State::Value => match b {
+                    b' ' | b'\t' => {}
+                    b'\r' => self.state = State::HeaderLf,
+                    b'\n' => {
+                        self.bare_lf()?;
+                        self.end_header();
+                    }
+                    _ if is_field_vchar(b) => self.value_end = pos + 1,
+                    _ => return Err(ParseError::InvalidHeaderValue),
+                },
+                State::HeaderLf => match b {
+                    b'\n' => self.end_header(),
+                    _ => return Err(ParseError::BareCr),
+                },
+                State::EndLf => match b {
+                    b'\n' => return Ok(Some(self.finish())),
+                    _ => return Err(ParseError::BareCr),
+                },
+            }
+        }
+        if self.pos < buf.len() {
+            return Err(ParseError::TooLarge);
+        }
+        Ok(None)
+    }
+
+    /// 跳过满足条件的字符
+    fn skip(&mut self, buf: &[u8], end: usize, accept: impl Fn(u8) -> bool) {
+        self.pos += buf[self.pos..end].iter().take_while(|b| accept(**b)).count();
+    }
+
+    /// 只有LF的行，严格模式下拒绝
+    fn bare_lf(&self) -> Result<(), ParseError> {
+        match self.strict {
+            true => Err(ParseError::BareLf),
+            false => Ok(()),
+        }
+    }
+
+    fn begin(&mut self, state: State, start: usize) {
+        self.state = state;
+        self.start = start;
+    }
+
+    fn end_header(&mut self) {
+        self.headers.push((self.name.clone(), self.start..self.value_end));
+        self.state = State::LineStart;
+    }
+
+    fn finish(&mut self) -> Head {
+        let head = Head {
+            method: self.method.clone(),
+            target: self.target.clone(),
+            version: self.version.clone(),
+            headers: std::mem::take(&mut self.headers),
+            size: self.pos,
+        };
+        *self = Self::new(self.max_size).strict(self.strict);
+        head
+    }
+}
+
+/// HTTP-version = "HTTP/" DIGIT "." DIGIT
+fn is_version(v: &[u8]) -> bool {
+    matches!(v, [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit())
+}
+
+/// 值中允许的可见字符，包括obs-text
+fn is_field_vchar(b: u8) -> bool {
+    matches!(b, 0x21..=0x7E | 0x80..=0xFF)
+}
+
+/// 解析出的请求头，各部分是在原始数据中的位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    method: Range<usize>,
+    target: Range<usize>,
+    version: Range<usize>,
+    headers: Vec<(Range<usize>, Range<usize>)>,
+    size: usize,
+}
+
+impl Head {
+    /// 请求头占用的字节数，包括结束的空行，之后是请求体
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// 请求头的内容，借用原始数据，不复制
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> RequestHead<'a> {
+    /// 解析完整的请求头，必须以空行结束
+    pub fn parse(raw: &'a str) -> Result<Self, ParseError> {
+        match HeadParser::new(usize::MAX).parse(raw.as_bytes())? {
+            Some(head) => Ok(Self::new(raw, &head)),
+            None => Err(ParseError::InvalidHeaderName),
+        }
+    }
+    /// `raw`是解析`head`时的数据
+    pub fn new(raw: &'a str, head: &Head) -> Self {
+        // 各部分的边界都是ASCII字符，不会切在UTF-8字符中间
+        let get = |range: &Range<usize>| &raw[range.clone()];
+        Self {
+            method: get(&head.method),
+            target: get(&head.target),
+            version: get(&head.version),
+            headers: head.headers.iter().map(|(name, value)| (get(name), get(value))).collect(),
+        }
+    }
+    /// 由各部分构造，如HTTP/2的请求
+    pub(crate) fn from_parts(method: &'a str, target: &'a str, version: &'a str, headers: Vec<(&'a str, &'a str)>) -> Self {
+        Self { method, target, version, headers }
+    }
+
+    pub fn method(&self) -> &'a str {
+        self.method
+    }
+    /// 请求目标，包括查询字符串
+    pub fn target(&self) -> &'a str {
+        self.target
+    }
+    pub fn version(&self) -> &'a str {
+        self.version
+    }
+    /// 按出现顺序的所有头，值已经去掉首尾空白
+    pub fn headers(&self) -> &[(&'a str, &'a str)] {
+        &self.headers
+    }
+    /// 第一个同名头的值，名称不区分大小写
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.get_all(name).next()
+    }
+    pub fn get_all<'b>(&'b self, name: &'b str) -> impl Iterator<Item=&'a str> + 'b {
+        self.headers.iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    }
+
+    /// 按RFC 9112检查Host和请求体长度相关的头，防止请求走私
+    /// 不严格时允许多个相同的Content-Length
+    pub fn validate(&self, strict: bool) -> Result<(), ParseError> {
+        // RFC 9112 3.2 HTTP/1.1请求必须有且只有一个Host
+        match self.get_all("host").count() {
+            0 if self.version != "HTTP/1.0" => return Err(ParseError::MissingHost),
+            0 | 1 => {}
+            _ => return Err(ParseError::MultipleHost),
+        }
+        // RFC 9112 6.3 值可以是逗号分隔的列表，都必须是相同的数字
+        let mut content_length = None;
+        for value in self.get_all("content-length").flat_map(|v| v.split(',')) {
+            let value = parse_content_length(value.trim())?;
+            match content_length {
+                None => content_length = Some(value),
+                Some(first) if first != value => return Err(ParseError::ConflictingContentLength),
+                Some(_) if strict => return Err(ParseError::DuplicateContentLength),
+                Some(_) => {}
+            }
+        }
+        let codings: Vec<&str> = self.get_all("transfer-encoding").flat_map(|v| v.split(',')).map(str::trim).collect();
+        if codings.is_empty() {
+            return Ok(());
+        }
+        // RFC 9112 6.1 同时存在时可能是请求走私，直接拒绝
+        if content_length.is_some() {
+            return Err(ParseError::ContentLengthWithTransferEncoding);
+        }
+        // 只会解码chunked，其他编码交给处理器会被当作原始数据，RFC 9112 6.1 返回501
+        match codings[..] {
+            [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(()),
+            _ => Err(ParseError::UnsupportedTransferEncoding),
+        }
+    }
+    /// 请求体的长度，[RequestHead::validate]之后调用
+    pub fn content_length(&self) -> Option<usize> {
+        let value = self.header("content-length")?.split(',').next()?;
+        parse_content_length(value.trim()).ok()
+    }
+    /// 是否使用分块编码，[RequestHead::validate]之后调用
+    pub fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding").is_some()
+    }
+}
+
+/// Content-Length = 1*DIGIT
+fn parse_content_length(value: &str) -> Result<usize, ParseError> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::InvalidContentLength);
+    }
+    value.parse().map_err(|_| ParseError::InvalidContentLength)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::{Head, HeadParser, ParseError, RequestHead};
+
+    const REQUESTS: &[&str] = &[
+        "GET / HTTP/1.1\r\n\r\n",
+        "GET /search?q=a+b HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n",
+        "\r\nPOST /upload HTTP/1.0\r\nContent-Length:  12 \r\nX-Empty:\r\nX-Tab:\tv\t1\t\r\n\r\n",
+        "OPTIONS * HTTP/1.1\nHost: a\n\n",
+    ];
+
+    /// 按指定位置分开送入解析器，不严格时接受只有LF的行
+    fn parse_split(raw: &[u8], splits: &[usize], strict: bool) -> Result<Option<Head>, ParseError> {
+        let mut parser = HeadParser::new(8192).strict(strict);
+        let mut ends: Vec<usize> = splits.iter().map(|s| s % (raw.len() + 1)).collect();
+        ends.push(raw.len());
+        ends.sort();
+        for end in ends {
+            if let Some(head) = parser.parse(&raw[..end])? {
+                return Ok(Some(head));
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn parses_request_heads() {
+        let raw = REQUESTS[2];
+        let head = RequestHead::parse(raw).unwrap();
+        assert_eq!((head.method(), head.target(), head.version()), ("POST", "/upload", "HTTP/1.0"));
+        assert_eq!(head.headers(), [("Content-Length", "12"), ("X-Empty", ""), ("X-Tab", "v\t1")]);
+        assert_eq!(head.header("content-length"), Some("12"));
+        let raw = REQUESTS[3];
+        assert_eq!(RequestHead::parse(raw), Err(ParseError::BareLf));
+        let head = HeadParser::new(8192).strict(false).parse(raw.as_bytes()).unwrap().unwrap();
+        let head = RequestHead::new(raw, &head);
+        assert_eq!((head.target(), head.header("HOST")), ("*", Some("a")));
+    }
+
+    #[test]
+    fn stops_at_end_of_head() {
+        let raw = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut parser = HeadParser::new(8192);
+        let head = parser.parse(raw).unwrap().unwrap();
+        assert_eq!(head.size(), 28);
+        // 解析器重置后可以解析下一个请求
+        let next = parser.parse(&raw[head.size()..]).unwrap().unwrap();
+        assert_eq!(next.size(), raw.len() - head.size());
+        assert_eq!(parser.parse(b"GET / HTTP/1.1\r\nHost").unwrap(), None);
+    }
+
+    #[test]
+    fn precise_errors() {
+        let error = |raw: &str| HeadParser::new(64).parse(raw.as_bytes()).unwrap_err();
+        assert_eq!(error("GE(T / HTTP/1.1\r\n\r\n"), ParseError::InvalidMethod);
+        assert_eq!(error(" GET / HTTP/1.1\r\n\r\n"), ParseError::InvalidMethod);
+        assert_eq!(error("GET  / HTTP/1.1\r\n\r\n"), ParseError::InvalidTarget);
+        assert_eq!(error("GET /a\x7fb HTTP/1.1\r\n\r\n"), ParseError::InvalidTarget);
+        assert_eq!(error("GET /\r\n\r\n"), ParseError::InvalidVersion);
+        assert_eq!(error("GET / HTTP/1.10\r\n\r\n"), ParseError::InvalidVersion);
+        assert_eq!(error("GET / http/1.1\r\n\r\n"), ParseError::InvalidVersion);
+        assert_eq!(error("GET / HTTP/2.0\r\n\r\n"), ParseError::UnsupportedVersion);
+        assert_eq!(error("GET / HTTP/3.0\r\n\r\n"), ParseError::UnsupportedVersion);
+        assert_eq!(error("GET / HTTP/0.9\r\n\r\n"), ParseError::UnsupportedVersion);
+        assert_eq!(error("GET / HTTP/1.1\r\nHo(st: a\r\n\r\n"), ParseError::InvalidHeaderName);
+        assert_eq!(error("GET / HTTP/1.1\r\nA: 1\0\r\n\r\n"), ParseError::InvalidHeaderValue);
+        assert_eq!(error("GET / HTTP/1.1\r\nHost a\r\n\r\n"), ParseError::WhitespaceBeforeColon);
+        assert_eq!(error("GET / HTTP/1.1\r\nHost : a\r\n\r\n"), ParseError::WhitespaceBeforeColon);
+        assert_eq!(error("GET / HTTP/1.1\r\nHost\r\n\r\n"), ParseError::MissingColon);
+        assert_eq!(error("GET / HTTP/1.1\r\nA: 1\r\n b\r\n\r\n"), ParseError::ObsFold);
+        assert_eq!(error("GET / HTTP/1.1\r\n\tA: 1\r\n\r\n"), ParseError::ObsFold);
+        assert_eq!(error("GET / HTTP/1.1\rX\n\r\n"), ParseError::BareCr);
+        assert_eq!(error("GET / HTTP/1.1\r\nA: 1\rB: 2\r\n\r\n"), ParseError::BareCr);
+        assert_eq!(error("GET / HTTP/1.1\r\nA: 1\r\n\rX"), ParseError::BareCr);
+        assert_eq!(error("\rGET / HTTP/1.1\r\n\r\n"), ParseError::BareCr);
+        assert_eq!(error("GET / HTTP/1.1\nA: 1\r\n\r\n"), ParseError::BareLf);
+        assert_eq!(error("GET / HTTP/1.1\r\nA: 1\n\r\n"), ParseError::BareLf);
+        assert_eq!(error("GET / HTTP/1.1\r\nA:\n\r\n"), ParseError::BareLf);
+        assert_eq!(error("GET / HTTP/1.1\r\n\n"), ParseError::BareLf);
+        assert_eq!(error("\nGET / HTTP/1.1\r\n\r\n"), ParseError::BareLf);
+        assert_eq!(error(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64))), ParseError::TooLarge);
+        assert_eq!(RequestHead::parse("GET / HTTP/1.1\r\n"), Err(ParseError::InvalidHeaderName));
+    }
+
+    #[test]
+    fn validates_framing_headers() {
+        let validate = |headers: &str, strict: bool| {
+            let raw = format!("POST / HTTP/1.1\r\n{}\r\n", headers);
+            RequestHead::parse(&raw).unwrap().validate(strict).map(|_| ())
+        };
+        let host = "Host: a\r\n";
+        assert_eq!(validate(host, true), Ok(()));
+        assert_eq!(validate("", true), Err(ParseError::MissingHost));
+        assert_eq!(validate("Host: a\r\nhost: b\r\n", true), Err(ParseError::MultipleHost));
+        assert_eq!(RequestHead::parse("GET / HTTP/1.0\r\n\r\n").unwrap().validate(true), Ok(()));
+        let cl = |value: &str, strict: bool| validate(&format!("{}Content-Length: {}\r\n", host, value), strict);
+        assert_eq!(cl("12", true), Ok(()));
+        for invalid in ["", "-1", "+1", "1.0", "0x10", "1 2", "99999999999999999999999"] {
+            assert_eq!(cl(invalid, false), Err(ParseError::InvalidContentLength), "{:?}", invalid);
+        }
+        assert_eq!(cl("1, 2", false), Err(ParseError::ConflictingContentLength));
+        assert_eq!(cl("5, 5", true), Err(ParseError::DuplicateContentLength));
+        assert_eq!(cl("5, 5", false), Ok(()));
+        let two = |a: &str, b: &str, strict: bool| {
+            validate(&format!("{}Content-Length: {}\r\nContent-Length: {}\r\n", host, a, b), strict)
+        };
+        assert_eq!(two("5", "6", false), Err(ParseError::ConflictingContentLength));
+        assert_eq!(two("5", "5", true), Err(ParseError::DuplicateContentLength));
+        assert_eq!(two("5", "5", false), Ok(()));
+        let te = |value: &str| validate(&format!("{}Transfer-Encoding: {}\r\n", host, value), true);
+        assert_eq!(te("chunked"), Ok(()));
+        assert_eq!(te("Chunked"), Ok(()));
+        assert_eq!(te("gzip, chunked"), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(te("gzip"), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(te("chunked, gzip"), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(te("chunked, chunked"), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(te(""), Err(ParseError::UnsupportedTransferEncoding));
+        assert_eq!(validate(&format!("{}Transfer-Encoding: chunked\r\nContent-Length: 5\r\n", host), false),
+                   Err(ParseError::ContentLengthWithTransferEncoding));
+        let head = RequestHead::parse("POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n").unwrap();
+        assert_eq!(head.content_length(), Some(5));
+    }
+
+    #[test]
+    fn every_split_point() {
+        for raw in REQUESTS {
+            let raw = raw.as_bytes();
+            let whole = parse_split(raw, &[], false).unwrap().unwrap();
+            assert_eq!(whole.size(), raw.len());
+            for i in 0..raw.len() {
+                for j in i..raw.len() {
+                    assert_eq!(parse_split(raw, &[i, j], false).unwrap().unwrap(), whole);
+                }
+            }
+        }
+    }
+
+    fn header_value() -> impl Strategy<Value=String> {
+        "[!-~]([ \t!-~]{0,20}[!-~])?"
+    }
+
+    proptest! {
+        // 任意输入不会panic，分开送入的结果与一次送入相同
+        #[test]
+        fn arbitrary_bytes(raw in proptest::collection::vec(any::<u8>(), 0..256),
+                           splits in proptest::collection::vec(any::<usize>(), 0..8),
+                           strict in any::<bool>()) {
+            prop_assert_eq!(parse_split(&raw, &splits, strict), parse_split(&raw, &[], strict));
+        }
+
+        // 在合法请求中随机修改一个字节
+        #[test]
+        fn mutated_requests(index in 0..REQUESTS.len(), at in any::<usize>(), byte in any::<u8>(),
+                            splits in proptest::collection::vec(any::<usize>(), 0..8),
+                            strict in any::<bool>()) {
+            let mut raw = REQUESTS[index].as_bytes().to_vec();
+            let at = at % raw.len();
+            raw[at] = byte;
+            prop_assert_eq!(parse_split(&raw, &splits, strict), parse_split(&raw, &[], strict));
+        }
+
+        // 生成的请求能还原出各部分
+        #[test]
+        fn generated_requests(method in "[A-Z]{1,10}",
+                              target in "/[!-~]{0,40}",
+                              headers in proptest::collection::vec(("[a-zA-Z0-9-]{1,16}", header_value()), 0..10),
+                              splits in proptest::collection::vec(any::<usize>(), 0..8)) {
+            let mut raw = format!("{} {} HTTP/1.1\r\n", method, target);
+            for (name, value) in &headers {
+                raw.push_str(&format!("{}: {} \r\n", name, value));
+            }
+            raw.push_str("\r\nbody");
+            let head = parse_split(raw.as_bytes(), &splits, true).unwrap().unwrap();
+            prop_assert_eq!(head.size(), raw.len() - 4);
+            let view = RequestHead::new(&raw, &head);
+            prop_assert_eq!((view.method(), view.target(), view.version()), (method.as_str(), target.as_str(), "HTTP/1.1"));
+            let parsed: Vec<_> = view.headers().iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            prop_assert_eq!(parsed, headers);
+        }
+    }
+}
@@ -1,25 +1,67 @@
 use std::collections::BTreeMap;
+use serde::de::DeserializeOwned;
 use crate::constant;
-use crate::error::{Fail, Result};
-use crate::utils::split;
+use crate::cookie::CookieJar;
+use crate::error::{Error, ErrorPages, Result};
+use crate::header::HeaderMap;
+use crate::json::{is_json, JsonError};
+use crate::multipart::{MultipartLimits, MultipartParser, Part};
+use crate::params::Params;
+use crate::parser::RequestHead;
+use crate::response::HttpResponse;
+use crate::session::Session;
+use crate::state::State;
 
-/// 支持的http方法
-#[derive(Debug, PartialEq)]
+/// http方法，RFC 9110定义的方法以及PATCH，其他合法的token作为扩展方法
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum HttpMethod {
-    Unknown,
-    Options,
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Extension(String),
 }
 
-// 实现字符串的into()方法
+impl HttpMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Connect => "CONNECT",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Trace => "TRACE",
+            HttpMethod::Patch => "PATCH",
+            HttpMethod::Extension(method) => method,
+        }
+    }
+    /// 服务器默认实现的方法，其他方法只有注册了路由才支持，否则返回501
+    pub fn is_implemented(&self) -> bool {
+        !matches!(self, HttpMethod::Connect | HttpMethod::Trace | HttpMethod::Extension(_))
+    }
+}
+
+// 实现字符串的into()方法，方法名区分大小写
 impl From<&str> for HttpMethod {
     fn from(s: &str) -> Self {
         match s {
-            "OPTIONS" => HttpMethod::Options,
             "GET" => HttpMethod::Get,
+            "HEAD" => HttpMethod::Head,
             "POST" => HttpMethod::Post,
-            _ => HttpMethod::Unknown,
+            "PUT" => HttpMethod::Put,
+            "DELETE" => HttpMethod::Delete,
+            "CONNECT" => HttpMethod::Connect,
+            "OPTIONS" => HttpMethod::Options,
+            "TRACE" => HttpMethod::Trace,
+            "PATCH" => HttpMethod::Patch,
+            _ => HttpMethod::Extension(s.to_string()),
         }
     }
 }
@@ -28,6 +70,7 @@ impl From<&str> for HttpMethod {
 #[derive(Debug, PartialEq)]
 pub enum HttpVersion {
     Unknown,
+    V1_0,
     V1_1,
     V2_0,
 }
@@ -36,7 +79,10 @@ pub enum HttpVersion {
 impl From<&str> for HttpVersion {
     fn from(s: &str) -> Self {
         match s {
-            "HTTP/1.1" => HttpVersion::V1_1,
+            "HTTP/1.0" => HttpVersion::V1_0,
+            // RFC 9110 2.5 更高的次版本按支持的最高次版本处理
+            s if s.starts_with("HTTP/1.") => HttpVersion::V1_1,
+            // 只来自HTTP/2的流，文本格式的请求行已经在解析时拒绝
             "HTTP/2.0" => HttpVersion::V2_0,
             _ => HttpVersion::Unknown,
         }
@@ -50,16 +96,37 @@ pub struct HttpRequest<'a> {
     method: HttpMethod,
     // 请求路径
     url: &'a str,
+    // 原始的查询字符串，不包括?
+    query: &'a str,
     // 请求版本
     version: HttpVersion,
     // 源ip
     ip: &'a str,
     // 请求头
-    headers: BTreeMap<String, &'a str>,
+    headers: HeaderMap,
+    // 请求头中的cookie
+    cookies: CookieJar,
     // 参数
-    search_params: BTreeMap<String, &'a str>,
+    search_params: Params,
     // 请求体
     body: BTreeMap<String, Vec<u8>>,
+    // urlencoded表单
+    form: Params,
+    // multipart表单的所有部分
+    parts: Vec<Part>,
+    // 分块编码请求体的尾部字段
+    trailers: BTreeMap<String, String>,
+    // 路由匹配到的路径参数
+    params: BTreeMap<String, String>,
+    // 中间件附加到请求上的数据
+    extensions: State,
+}
+
+/// 读取到的请求体
+pub(crate) enum RawBody {
+    Bytes(Vec<u8>),
+    /// 读取时已经解析好的multipart表单
+    Multipart(Vec<Part>),
 }
 
 impl<'a> HttpRequest<'a> {
@@ -67,70 +134,146 @@ impl<'a> HttpRequest<'a> {
                 raw_body: Vec<u8>,
                 ip: &'a str,
     ) -> Result<HttpRequest<'a>> {
-        let mut header = raw_header.lines();
-        // 获取请求行
-        let req_ln = header.next()
-            .ok_or_else(|| Fail::new("获取请求行失败"))?;
-        // 按照空格分割
-        let mut words = req_ln.split_whitespace();
-        // 获取请求方法
-        let method: HttpMethod = words.next()
-            .ok_or_else(|| Fail::new("无法解析请求方法"))?.into();
-        let mut search_params_raw = "";
-        let url = if let Some(full_url) = words.next() {
-            let mut split_url = full_url.splitn(2, '?');
-            let url = split_url.next().ok_or_else(|| Fail::new("无法解析请求地址"))?;
-            if let Some(params) = split_url.next() {
-                search_params_raw = params;
-            }
-            url
-        } else { "/" };
-        // 获取http版本
-        let version: HttpVersion = words.next()
-            .ok_or_else(|| Fail::new("无法解析http协议版本"))?.into();
-        // 读取请求头
-        let mut headers = BTreeMap::new();
-        for hl in header {
-            let mut split_hl = hl.splitn(2, ":");
-            if let (Some(key), Some(value)) = (split_hl.next(), split_hl.next()) {
-                headers.insert(key.trim().to_lowercase(), value.trim());
-            }
+        Self::parse(RequestHead::parse(raw_header)?, RawBody::Bytes(raw_body), ip, MultipartLimits::default())
+    }
+    /// `limits`用于请求体已经完整读取时解析multipart表单
+    pub(crate) fn parse(head: RequestHead<'a>,
+                        raw_body: RawBody,
+                        ip: &'a str,
+                        limits: MultipartLimits,
+    ) -> Result<HttpRequest<'a>> {
+        // 解析器已经验证过方法是token
+        let method: HttpMethod = head.method().into();
+        // 分割路径和查询字符串
+        let (url, search_params_raw) = head.target().split_once('?').unwrap_or((head.target(), ""));
+        let version: HttpVersion = head.version().into();
+        // 请求头
+        let mut headers = HeaderMap::new();
+        for (name, value) in head.headers() {
+            headers.try_append(name, *value)?;
         }
+        let cookies = CookieJar::parse(&headers);
         // 查询参数
-        let search_params = parse_parameters(search_params_raw, |v| v)?;
+        let search_params = Params::parse(search_params_raw);
         // 处理请求体
-        let body = parse_body(&headers, &raw_body)?;
+        let (body, form, parts) = parse_body(&headers, raw_body, limits)?;
         Ok(Self {
             method,
             url,
+            query: search_params_raw,
             version,
             ip,
             headers,
+            cookies,
             search_params,
             body,
+            form,
+            parts,
+            trailers: BTreeMap::new(),
+            params: BTreeMap::new(),
+            extensions: State::new(),
         })
     }
     pub fn method(&self) -> &HttpMethod {
         &self.method
     }
     pub fn url(&self) -> &str {
-        &self.url
+        self.url
+    }
+    pub fn query(&self) -> &str {
+        self.query
     }
     pub fn version(&self) -> &HttpVersion {
         &self.version
     }
     pub fn ip(&self) -> &str {
-        &self.ip
+        self.ip
     }
-    pub fn headers(&self) -> &BTreeMap<String, &'a str> {
+    pub fn headers(&self) -> &HeaderMap {
         &self.headers
     }
-    pub fn search_params(&self) -> &BTreeMap<String, &'a str> {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+    pub fn cookies(&self) -> &CookieJar {
+        &self.cookies
+    }
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name)
+    }
+    pub fn search_params(&self) -> &Params {
         &self.search_params
     }
+    /// urlencoded表单，其他类型的请求体为空
+    pub fn form(&self) -> &Params {
+        &self.form
+    }
+    /// multipart表单的所有部分，按出现顺序
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+    /// multipart表单中第一个同名的部分
+    pub fn part(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|p| p.name() == name)
+    }
+    /// 按名称存储的请求体，multipart表单中只包含内存中的部分
     pub fn body(&self) -> &BTreeMap<String, Vec<u8>> {
         &self.body
     }
+    /// 把JSON请求体解析为指定类型，Content-Type不是JSON或内容无效时返回错误
+    pub fn json<T: DeserializeOwned>(&self) -> std::result::Result<T, JsonError> {
+        let content_type = self.headers.get("content-type");
+        if !content_type.is_some_and(is_json) {
+            return Err(JsonError::UnsupportedMediaType(content_type.map(str::to_string)));
+        }
+        let body = self.body.get("__raw").map(Vec::as_slice).unwrap_or_default();
+        serde_json::from_slice(body).map_err(JsonError::Invalid)
+    }
+    pub fn trailers(&self) -> &BTreeMap<String, String> {
+        &self.trailers
+    }
+    pub(crate) fn set_trailers(&mut self, trailers: Vec<(String, String)>) {
+        self.trailers = trailers.into_iter().collect();
+    }
+    /// 路径参数，如路由 /users/:id 中的id
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.params
+    }
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+    pub(crate) fn set_params(&mut self, params: BTreeMap<String, String>) {
+        self.params = params;
+    }
+    /// 中间件附加的数据，按类型存取
+    pub fn extensions(&self) -> &State {
+        &self.extensions
+    }
+    pub fn extensions_mut(&mut self) -> &mut State {
+        &mut self.extensions
+    }
+    /// 使用服务器配置的[ErrorPages]生成错误响应，不经过服务器时使用默认设置
+    pub fn error_response(&self, err: &Error) -> HttpResponse {
+        match self.extensions.get::<ErrorPages>() {
+            Some(pages) => pages.render(err),
+            None => ErrorPages::new().render(err),
+        }
+    }
+    /// 当前会话，需要启用[crate::session::SessionMiddleware]
+    pub fn session(&self) -> Option<&Session> {
+        self.extensions.get::<Session>()
+    }
+    /// 是否保持连接，HTTP/1.1默认保持，HTTP/1.0默认关闭
+    pub fn keep_alive(&self) -> bool {
+        let has = |token: &str| self.headers.has_token("connection", token);
+        match self.version {
+            HttpVersion::V1_0 => has("keep-alive"),
+            _ => !has("close"),
+        }
+    }
     pub fn body_utf8(&self) -> BTreeMap<String, String> {
         let mut form = BTreeMap::new();
         for (k, v) in &self.body {
@@ -140,100 +283,133 @@ impl<'a> HttpRequest<'a> {
     }
 }
 
+/// 按名称存储的请求体
+type BodyMap = BTreeMap<String, Vec<u8>>;
+
 /// 处理请求体
-fn parse_body(headers: &BTreeMap<String, &str>, body: &[u8]) -> Result<BTreeMap<String, Vec<u8>>> {
-    let mut boundary = None;
+/// 返回按名称存储的请求体，urlencoded表单，以及multipart表单的所有部分
+fn parse_body(headers: &HeaderMap, body: RawBody, limits: MultipartLimits) -> Result<(BodyMap, Params, Vec<Part>)> {
+    let body = match body {
+        RawBody::Bytes(body) => body,
+        RawBody::Multipart(parts) => return Ok((multipart_map(&parts), Params::new(), parts)),
+    };
     // 获取content-type
-    let content_type = match headers.get("content-type") {
-        None => constant::TEXT_PLAIN,
-        Some(&s) => {
-            let c_type = s.trim();
-            for part in s.split(';') {
-                let part = part.trim();
-                if part.starts_with("boundary=") {
-                    boundary = part.split('=').nth(1);
-                }
-            }
-            c_type
+    let content_type = headers.content_type()
+        .map(str::to_lowercase)
+        .unwrap_or_else(|| constant::TEXT_PLAIN.to_string());
+    if content_type == constant::APPLICATION_X_WWW_FORM_URLENCODED {
+        // 普通表单，同名的参数在body中只保留第一个值
+        let form = Params::parse(&body);
+        let mut map = BTreeMap::new();
+        for (k, v) in form.iter() {
+            map.entry(k.to_string()).or_insert_with(|| v.as_bytes().to_vec());
         }
-    };
-    if content_type.starts_with(constant::APPLICATION_X_WWW_FORM_URLENCODED) {
-        // 普通表单
-        parse_parameters(&String::from_utf8(body.to_vec())?, |v| {
-            v.as_bytes().to_vec()
-        })
-    } else if content_type.starts_with(constant::MULTIPART_FORM_DATA) {
+        Ok((map, form, Vec::new()))
+    } else if content_type == constant::MULTIPART_FORM_DATA {
         // Multipart表单
-        parse_multipart_form(body, boundary.ok_or_else(|| Fail::new("没有有效的boundary"))?)
+        let raw_content_type = headers.get("content-type").unwrap_or_default();
+        let mut parser = MultipartParser::from_content_type(raw_content_type, limits)?
+            .ok_or_else(|| Error::bad_request("没有有效的boundary"))?;
+        parser.feed(&body)?;
+        let parts = parser.finish()?;
+        Ok((multipart_map(&parts), Params::new(), parts))
     } else {
         // 其他类型存储为原始字节
         let mut map = BTreeMap::new();
-        map.insert(String::from("__raw"), body.to_vec());
-        Ok(map)
+        map.insert(String::from("__raw"), body);
+        Ok((map, Params::new(), Vec::new()))
     }
 }
 
-/// 转换MultipartForm
-fn parse_multipart_form(body: &[u8], boundary: &str) -> Result<BTreeMap<String, Vec<u8>>> {
-    let mut params = BTreeMap::new();
-    // 拆分
-    let mut sections = split(&body, format!("--{}\r\n", boundary));
-    let last_sep = format!("--{}--\r\n", boundary);
-    // 去掉空的第一部分
-    sections.remove(0);
-    for mut section in sections {
-        // 检查是否是最后一部分
-        if section.ends_with(last_sep.as_bytes()) {
-            // 去除最后一部分
-            section = &section[..(section.len() - last_sep.len() - 2)];
-        }
-        let lines = split(&section, b"\r\n");
-        let name = String::from_utf8_lossy(lines[0])
-            .split(';')
-            .map(|s| s.trim())
-            .find_map(|s| {
-                if s.starts_with("name=") {
-                    let name = s.split('=').nth(1)?;
-                    Some(name[1..(name.len() - 1)].to_lowercase())
-                } else { None }
-            })
-            .ok_or_else(|| Fail::new("表单内容没有name属性"))?;
-        // TODO 获取每个section中的content-type
-        // 查找数据行
-        let mut data_line_idx = 0_usize;
-        for &l in &lines {
-            data_line_idx += 1;
-            if l.len() == 0 {
-                break;
-            }
+/// multipart表单中内存里的部分按名称存储，同名的只保留第一个
+fn multipart_map(parts: &[Part]) -> BodyMap {
+    let mut map = BTreeMap::new();
+    for part in parts {
+        if let Some(bytes) = part.bytes() {
+            map.entry(part.name().to_string()).or_insert_with(|| bytes.to_vec());
         }
-        // 获取值
-        let data_line = lines
-            .get(data_line_idx)
-            .ok_or_else(|| Fail::new("表单内容损坏"))?;
-        let value = data_line.to_vec();
-        params.insert(name, value);
-    }
-    Ok(params)
+    }
+    map
 }
 
-/// 转换表单和查询参数
-fn parse_parameters<'a, V>(raw: &'a str, process_value: fn(&'a str) -> V)
-                           -> Result<BTreeMap<String, V>> {
-    let mut params = BTreeMap::new();
-    // 分割参数
-    for p in raw.split('&') {
-        // 分割key和value
-        let mut ps = p.splitn(2, '=');
-        params.insert(
-            ps.next()
-                .ok_or_else(|| Fail::new("损坏的参数"))?
-                .trim()
-                .to_lowercase(),
-            process_value(if let Some(value) = ps.next() {
-                value.trim()
-            } else { "" }),
-        );
-    }
-    Ok(params)
+#[cfg(test)]
+mod tests {
+    use crate::multipart::MultipartLimits;
+    use crate::parser::RequestHead;
+    use crate::response::HttpStatus;
+    use super::{HttpRequest, RawBody};
+
+    #[test]
+    fn multipart_uses_given_limits() {
+        let raw = "POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=B\r\n\r\n";
+        let body = b"--B\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n0123456789\r\n--B--".to_vec();
+        let head = RequestHead::parse(raw).unwrap();
+        let limits = MultipartLimits { max_part_size: 4, ..Default::default() };
+        let err = HttpRequest::parse(head, RawBody::Bytes(body.clone()), "127.0.0.1", limits).unwrap_err();
+        assert_eq!(err.status(), HttpStatus::ContentTooLarge);
+        assert!(HttpRequest::from(raw, body, "127.0.0.1").is_ok());
+    }
+
+    #[test]
+    fn keep_alive_defaults_and_overrides() {
+        let cases = [
+            ("HTTP/1.0", "", false),
+            ("HTTP/1.0", "Connection: keep-alive\r\n", true),
+            ("HTTP/1.0", "Connection: Keep-Alive\r\n", true),
+            ("HTTP/1.1", "", true),
+            ("HTTP/1.1", "Connection: close\r\n", false),
+            ("HTTP/1.1", "Connection: Upgrade, Close\r\n", false),
+            ("HTTP/1.1", "Connection: keep-alive\r\n", true),
+        ];
+        for (version, headers, keep_alive) in cases {
+            let raw = format!("GET / {}\r\nHost: a\r\n{}\r\n", version, headers);
+            let req = HttpRequest::from(&raw, Vec::new(), "127.0.0.1").unwrap();
+            assert_eq!(req.keep_alive(), keep_alive, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn keeps_repeated_headers() {
+        let raw = "GET / HTTP/1.1\r\nAccept: text/html\r\nCookie: a=1\r\ncookie: b=2\r\nAccept: */*\r\n\r\n";
+        let req = HttpRequest::from(raw, Vec::new(), "127.0.0.1").unwrap();
+        assert_eq!(req.headers().get_all("cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(req.headers().get_list("accept").collect::<Vec<_>>(), ["text/html", "*/*"]);
+        assert_eq!(req.header("COOKIE"), Some("a=1"));
+        assert_eq!(req.cookies().iter().collect::<Vec<_>>(), [("a", "1"), ("b", "2")]);
+        assert_eq!(req.cookie("b"), Some("2"));
+    }
+
+    #[test]
+    fn decodes_query_and_form() {
+        let raw = "POST /search?q=rust+http&tag=a&tag=b HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n";
+        let body = b"Name=%E5%BC%A0%E4%B8%89&Name=x".to_vec();
+        let req = HttpRequest::from(raw, body, "127.0.0.1").unwrap();
+        assert_eq!(req.url(), "/search");
+        assert_eq!(req.search_params().get("q"), Some("rust http"));
+        assert_eq!(req.search_params().get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(req.form().get_all("Name").collect::<Vec<_>>(), ["张三", "x"]);
+        assert_eq!(req.body_utf8().get("Name").map(String::as_str), Some("张三"));
+    }
+
+    #[test]
+    fn parses_multipart_form() {
+        let raw = "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=\"b\"\r\n\r\n";
+        let body = b"--b\r\nContent-Disposition: form-data; name=\"Note\"\r\n\r\nline 1\r\nline 2\r\n\
+--b\r\nContent-Disposition: form-data; name=\"img\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n\x1a\n\r\n--b--\r\n";
+        let req = HttpRequest::from(raw, body.to_vec(), "127.0.0.1").unwrap();
+        assert_eq!(req.body_utf8().get("Note").map(String::as_str), Some("line 1\r\nline 2"));
+        let img = req.part("img").unwrap();
+        assert_eq!(img.filename(), Some("a.png"));
+        assert_eq!(img.content_type(), Some("image/png"));
+        assert_eq!(img.bytes(), Some(&b"\x89PNG\r\n\x1a\n"[..]));
+        assert_eq!(req.parts().len(), 2);
+    }
+
+    #[test]
+    fn empty_query_has_no_params() {
+        let req = HttpRequest::from("GET /? HTTP/1.1\r\n\r\n", Vec::new(), "127.0.0.1").unwrap();
+        assert!(req.search_params().is_empty());
+        let req = HttpRequest::from("GET / HTTP/1.1\r\n\r\n", Vec::new(), "127.0.0.1").unwrap();
+        assert!(req.search_params().is_empty());
+    }
 }
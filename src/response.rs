@@ -1,43 +1,220 @@
-use std::collections::{BTreeMap};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::io;
+use std::path::Path;
+use serde::Serialize;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use crate::constant;
+use crate::cookie::Cookie;
+use crate::error::Result;
+use crate::header::HeaderMap;
 
-/// http状态码
-#[derive(Debug, PartialEq, Clone)]
-pub enum HttpStatus {
-    Ok,
-    BadRequest,
-    NotFound,
-    InternalServerError,
+/// 生成http状态码枚举，每一行为 状态码 => 名称, 原因短语
+macro_rules! http_status {
+    ($($code:literal => $name:ident, $reason:literal;)*) => {
+        /// http状态码，包含IANA注册的全部状态码
+        #[derive(Debug, PartialEq, Eq, Clone)]
+        pub enum HttpStatus {
+            $($name,)*
+            /// 未注册的状态码和原因短语，使用[HttpStatus::custom]创建
+            Custom(u16, String),
+        }
+
+        impl HttpStatus {
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(HttpStatus::$name => $code,)*
+                    HttpStatus::Custom(code, _) => *code,
+                }
+            }
+            pub fn reason(&self) -> &str {
+                match self {
+                    $(HttpStatus::$name => $reason,)*
+                    HttpStatus::Custom(_, reason) => reason,
+                }
+            }
+            /// 根据数字获取状态码，未注册的状态码使用空的原因短语
+            pub fn from_code(code: u16) -> Option<HttpStatus> {
+                match code {
+                    $($code => Some(HttpStatus::$name),)*
+                    100..=999 => Some(HttpStatus::Custom(code, String::new())),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+http_status! {
+    100 => Continue, "Continue";
+    101 => SwitchingProtocols, "Switching Protocols";
+    102 => Processing, "Processing";
+    103 => EarlyHints, "Early Hints";
+    200 => Ok, "OK";
+    201 => Created, "Created";
+    202 => Accepted, "Accepted";
+    203 => NonAuthoritativeInformation, "Non-Authoritative Information";
+    204 => NoContent, "No Content";
+    205 => ResetContent, "Reset Content";
+    206 => PartialContent, "Partial Content";
+    207 => MultiStatus, "Multi-Status";
+    208 => AlreadyReported, "Already Reported";
+    226 => ImUsed, "IM Used";
+    300 => MultipleChoices, "Multiple Choices";
+    301 => MovedPermanently, "Moved Permanently";
+    302 => Found, "Found";
+    303 => SeeOther, "See Other";
+    304 => NotModified, "Not Modified";
+    305 => UseProxy, "Use Proxy";
+    307 => TemporaryRedirect, "Temporary Redirect";
+    308 => PermanentRedirect, "Permanent Redirect";
+    400 => BadRequest, "Bad Request";
+    401 => Unauthorized, "Unauthorized";
+    402 => PaymentRequired, "Payment Required";
+    403 => Forbidden, "Forbidden";
+    404 => NotFound, "Not Found";
+    405 => MethodNotAllowed, "Method Not Allowed";
+    406 => NotAcceptable, "Not Acceptable";
+    407 => ProxyAuthenticationRequired, "Proxy Authentication Required";
+    408 => RequestTimeout, "Request Timeout";
+    409 => Conflict, "Conflict";
+    410 => Gone, "Gone";
+    411 => LengthRequired, "Length Required";
+    412 => PreconditionFailed, "Precondition Failed";
+    413 => ContentTooLarge, "Content Too Large";
+    414 => UriTooLong, "URI Too Long";
+    415 => UnsupportedMediaType, "Unsupported Media Type";
+    416 => RangeNotSatisfiable, "Range Not Satisfiable";
+    417 => ExpectationFailed, "Expectation Failed";
+    418 => ImATeapot, "I'm a teapot";
+    421 => MisdirectedRequest, "Misdirected Request";
+    422 => UnprocessableContent, "Unprocessable Content";
+    423 => Locked, "Locked";
+    424 => FailedDependency, "Failed Dependency";
+    425 => TooEarly, "Too Early";
+    426 => UpgradeRequired, "Upgrade Required";
+    428 => PreconditionRequired, "Precondition Required";
+    429 => TooManyRequests, "Too Many Requests";
+    431 => RequestHeaderFieldsTooLarge, "Request Header Fields Too Large";
+    451 => UnavailableForLegalReasons, "Unavailable For Legal Reasons";
+    500 => InternalServerError, "Internal Server Error";
+    501 => NotImplemented, "Not Implemented";
+    502 => BadGateway, "Bad Gateway";
+    503 => ServiceUnavailable, "Service Unavailable";
+    504 => GatewayTimeout, "Gateway Timeout";
+    505 => HttpVersionNotSupported, "HTTP Version Not Supported";
+    506 => VariantAlsoNegotiates, "Variant Also Negotiates";
+    507 => InsufficientStorage, "Insufficient Storage";
+    508 => LoopDetected, "Loop Detected";
+    510 => NotExtended, "Not Extended";
+    511 => NetworkAuthenticationRequired, "Network Authentication Required";
 }
 
-/// toString
 impl HttpStatus {
-    fn to_str<'a>(&self) -> &'a str {
+    /// 创建自定义状态码，状态码必须是三位数，原因短语不能包含控制字符
+    pub fn custom(code: u16, reason: &str) -> Option<HttpStatus> {
+        if !(100..=999).contains(&code) || reason.chars().any(|c| c.is_control()) {
+            return None;
+        }
+        Some(HttpStatus::Custom(code, reason.to_string()))
+    }
+    /// 1xx、204和304的响应不能包含响应体
+    pub fn allows_body(&self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
+}
+
+/// toString
+impl Display for HttpStatus {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "{} {}", self.code(), self.reason())
+    }
+}
+
+/// 响应体
+pub enum Body {
+    Empty,
+    /// 内存中的数据
+    Bytes(Vec<u8>),
+    /// 长度已知的文件
+    File(File, u64),
+    /// 长度未知的流，使用分块编码写出
+    Stream(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+impl Body {
+    /// 打开文件作为响应体
+    pub async fn file(path: impl AsRef<Path>) -> io::Result<Body> {
+        let file = File::open(path).await?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "不是文件"));
+        }
+        Ok(Body::File(file, metadata.len()))
+    }
+    /// 使用任意异步读取器作为响应体
+    pub fn stream(reader: impl AsyncRead + Send + Unpin + 'static) -> Body {
+        Body::Stream(Box::new(reader))
+    }
+    /// 长度未知时返回None
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(b) => Some(b.len() as u64),
+            Body::File(_, len) => Some(*len),
+            Body::Stream(_) => None,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<Option<Vec<u8>>> for Body {
+    fn from(bytes: Option<Vec<u8>>) -> Self {
+        bytes.map_or(Body::Empty, Body::Bytes)
+    }
+}
+
+impl Debug for Body {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
         match self {
-            HttpStatus::Ok => "200 OK",
-            HttpStatus::BadRequest => "400 Bad Request",
-            HttpStatus::NotFound => "404 Not Found",
-            HttpStatus::InternalServerError => "500 Internal Server Error",
+            Body::Empty => write!(formatter, "Empty"),
+            Body::Bytes(b) => write!(formatter, "Bytes({})", b.len()),
+            Body::File(_, len) => write!(formatter, "File({})", len),
+            Body::Stream(_) => write!(formatter, "Stream"),
         }
     }
 }
 
 /// http响应
-#[derive(Debug, PartialEq, Clone)]
-pub struct HttpResponse<'a> {
-    version: &'a str,
+#[derive(Debug)]
+pub struct HttpResponse {
+    version: &'static str,
     status: HttpStatus,
-    headers: BTreeMap<&'a str, &'a str>,
-    body: Option<Vec<u8>>,
+    headers: HeaderMap,
+    body: Body,
+    // 长度未知的响应体是否使用分块编码，否则写完后关闭连接
+    chunked: bool,
+    // 只写出响应头，用于HEAD请求
+    head_only: bool,
 }
 
-impl<'a> Default for HttpResponse<'a> {
+impl Default for HttpResponse {
     fn default() -> Self {
         let mut response = Self {
             version: "HTTP/1.1",
             status: HttpStatus::Ok,
-            headers: BTreeMap::new(),
-            body: None,
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+            chunked: true,
+            head_only: false,
         };
         response.headers.insert("Content-Type", constant::TEXT_PLAIN);
         response.headers.insert("server", "FlapyPan/my-http-server");
@@ -45,57 +222,265 @@ impl<'a> Default for HttpResponse<'a> {
     }
 }
 
-impl<'a> HttpResponse<'a> {
+impl HttpResponse {
     pub fn new(status: HttpStatus,
-               headers: Option<BTreeMap<&'a str, &'a str>>,
-               body: Option<Vec<u8>>,
-    ) -> HttpResponse<'a> {
-        let mut response: HttpResponse<'a> = HttpResponse::default();
-        response.status = status;
-        match headers {
-            None => {}
-            Some(hs) => {
-                for (k, v) in hs {
-                    response.headers.insert(k, v);
-                }
+               headers: Option<HeaderMap>,
+               body: impl Into<Body>,
+    ) -> HttpResponse {
+        let mut response = HttpResponse { status, ..Default::default() };
+        if let Some(hs) = headers {
+            // 传入的头替换默认值，同名的多个值全部保留
+            for (k, _) in hs.iter() {
+                response.headers.remove(k);
+            }
+            for (k, v) in hs.iter() {
+                response.headers.append(k, v);
             }
         }
-        response.body = body;
+        response.body = body.into();
         response
     }
-    pub fn not_found(body: Option<Vec<u8>>) -> HttpResponse<'a> {
-        let mut response: HttpResponse<'a> = HttpResponse::default();
-        response.status = HttpStatus::NotFound;
+    /// JSON响应，序列化失败时返回500
+    pub fn json<T: Serialize + ?Sized>(status: HttpStatus, value: &T) -> HttpResponse {
+        match serde_json::to_vec(value) {
+            Ok(body) => {
+                let mut response = HttpResponse { status, body: body.into(), ..Default::default() };
+                response.headers.insert("Content-Type", constant::APPLICATION_JSON);
+                response
+            }
+            Err(err) => {
+                // 不把序列化错误的细节返回给客户端
+                println!("{}", err);
+                let status = HttpStatus::InternalServerError;
+                HttpResponse::new(status.clone(), None, Some(status.reason().as_bytes().to_vec()))
+            }
+        }
+    }
+    pub fn not_found(body: Option<Vec<u8>>) -> HttpResponse {
+        let mut response = HttpResponse {
+            status: HttpStatus::NotFound,
+            body: body.into(),
+            ..Default::default()
+        };
         response.headers.insert("Content-Type", constant::TEXT_HTML);
-        response.body = body;
         response
     }
-    fn headers(&self) -> String {
-        let map = self.headers.clone();
-        let mut header_string: String = "".into();
-        for (k, v) in map.iter() {
-            header_string = format!("{}{}:{}\r\n", header_string, k, v);
-        }
-        header_string
+    /// 设置响应头，替换同名的旧值，名称或值不合法时panic，动态的内容使用[HttpResponse::try_set_header]
+    pub fn set_header(&mut self, key: &str, value: impl Into<String>) {
+        self.headers.insert(key, value);
+    }
+    pub fn try_set_header(&mut self, key: &str, value: impl Into<String>) -> Result<()> {
+        self.headers.try_insert(key, value)
+    }
+    /// 追加响应头，如多个Set-Cookie
+    pub fn append_header(&mut self, key: &str, value: impl Into<String>) {
+        self.headers.append(key, value);
+    }
+    pub fn try_append_header(&mut self, key: &str, value: impl Into<String>) -> Result<()> {
+        self.headers.try_append(key, value)
+    }
+    /// 追加一个Set-Cookie头，同一个响应可以设置多个cookie
+    pub fn set_cookie(&mut self, cookie: &Cookie) {
+        self.headers.append("Set-Cookie", cookie.to_string());
+    }
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
+    }
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+    pub fn status(&self) -> &HttpStatus {
+        &self.status
+    }
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+    pub fn set_body(&mut self, body: impl Into<Body>) {
+        self.body = body.into();
+    }
+    /// 长度未知的响应体不使用分块编码，由关闭连接表示结束，用于HTTP/1.0
+    pub(crate) fn disable_chunked(&mut self) {
+        self.chunked = false;
     }
-    /// 转换为字节数组
-    pub fn to_vec(&self) -> Vec<u8> {
-        let mut vec = format!(
-            "{} {}\r\n{}Content-Length: {}\r\n\r\n",
+    /// 只写出响应头，响应头与完整响应相同
+    pub(crate) fn set_head_only(&mut self) {
+        self.head_only = true;
+    }
+    /// 转换状态行和响应头
+    fn head(&self) -> String {
+        let framing = match self.body.len() {
+            // 不能包含响应体的状态码也不发送长度
+            _ if !self.status.allows_body() => String::new(),
+            Some(len) => format!("Content-Length: {}\r\n", len),
+            None if self.chunked => "Transfer-Encoding: chunked\r\n".to_string(),
+            None => String::new(),
+        };
+        // 长度由响应体决定，忽略手动设置的值
+        let mut headers = self.headers.clone();
+        headers.remove("content-length");
+        headers.remove("transfer-encoding");
+        format!(
+            "{} {}\r\n{}{}\r\n",
             &self.version,
-            &self.status.to_str(),
-            &self.headers(),
-            match &self.body {
-                None => 0,
-                Some(b) => b.len()
-            },
-        ).as_bytes().to_vec();
-        match &self.body {
-            None => {}
-            Some(b) => {
-                vec.append(b.to_vec().as_mut());
+            &self.status,
+            headers,
+            framing,
+        )
+    }
+    /// 拆分为状态、响应头和响应体，HTTP/2按帧发送
+    pub(crate) fn into_parts(self) -> (HttpStatus, HeaderMap, Body) {
+        (self.status, self.headers, self.body)
+    }
+    /// 写出响应
+    pub async fn write_to<W: AsyncWrite + Unpin>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.head().as_bytes()).await?;
+        if self.head_only || !self.status.allows_body() {
+            return Ok(());
+        }
+        match self.body {
+            Body::Empty => {}
+            Body::Bytes(b) => writer.write_all(&b).await?,
+            Body::File(file, len) => {
+                tokio::io::copy(&mut file.take(len), writer).await?;
             }
-        };
-        vec
+            Body::Stream(mut reader) => {
+                let mut buf = vec![0u8; 8192];
+                loop {
+                    let length = reader.read(&mut buf).await?;
+                    if length == 0 {
+                        break;
+                    }
+                    if self.chunked {
+                        writer.write_all(format!("{:X}\r\n", length).as_bytes()).await?;
+                        writer.write_all(&buf[..length]).await?;
+                        writer.write_all(b"\r\n").await?;
+                    } else {
+                        writer.write_all(&buf[..length]).await?;
+                    }
+                    // 每读到一段数据就发送给客户端
+                    writer.flush().await?;
+                }
+                if self.chunked {
+                    writer.write_all(b"0\r\n\r\n").await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cookie::Cookie;
+    use crate::header::HeaderMap;
+    use super::{Body, HttpResponse, HttpStatus};
+
+    async fn write(response: HttpResponse) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn status_line_uses_registered_reason() {
+        assert_eq!(HttpStatus::Ok.to_string(), "200 OK");
+        assert_eq!(HttpStatus::ContentTooLarge.to_string(), "413 Content Too Large");
+        assert_eq!(HttpStatus::from_code(429), Some(HttpStatus::TooManyRequests));
+        assert_eq!(HttpStatus::from_code(503).unwrap().reason(), "Service Unavailable");
+    }
+
+    #[test]
+    fn custom_codes() {
+        assert_eq!(HttpStatus::from_code(299), Some(HttpStatus::Custom(299, String::new())));
+        assert_eq!(HttpStatus::from_code(1000), None);
+        assert_eq!(HttpStatus::from_code(42), None);
+        let status = HttpStatus::custom(599, "Network Connect Timeout").unwrap();
+        assert_eq!(status.to_string(), "599 Network Connect Timeout");
+        assert_eq!(HttpStatus::custom(599, "bad\r\nX-Injected: 1"), None);
+    }
+
+    #[tokio::test]
+    async fn multiple_set_cookie_headers() {
+        let mut response = HttpResponse::new(HttpStatus::NoContent, None, None);
+        response.set_cookie(&Cookie::new("a", "1").http_only(true));
+        response.set_cookie(&Cookie::removal("b"));
+        let out = write(response).await;
+        assert!(out.contains("\r\nSet-Cookie: a=1; HttpOnly\r\n"));
+        assert!(out.contains("\r\nSet-Cookie: b=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0\r\n"));
+    }
+
+    #[tokio::test]
+    async fn keeps_repeated_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Set-Cookie", "b=2");
+        headers.insert("Content-Type", "text/html");
+        let response = HttpResponse::new(HttpStatus::Ok, Some(headers), None);
+        assert_eq!(response.headers().get_all("set-cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(response.headers().get_all("content-type").collect::<Vec<_>>(), ["text/html"]);
+        let out = write(response).await;
+        assert!(out.contains("\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\n"), "{}", out);
+    }
+
+    #[tokio::test]
+    async fn json_body() {
+        let response = HttpResponse::json(HttpStatus::Created, &serde_json::json!({ "id": 1 }));
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        let out = write(response).await;
+        assert!(out.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(out.ends_with("\r\n\r\n{\"id\":1}"));
+    }
+
+    #[tokio::test]
+    async fn no_body_for_204_and_304() {
+        for status in [HttpStatus::NoContent, HttpStatus::NotModified] {
+            let response = HttpResponse::new(status, None, Some(b"ignored".to_vec()));
+            let raw = write(response).await;
+            assert!(!raw.contains("Content-Length"));
+            assert!(raw.ends_with("\r\n\r\n"));
+        }
+        let response = HttpResponse::new(HttpStatus::Created, None, Body::Bytes(b"ok".to_vec()));
+        let raw = write(response).await;
+        assert!(raw.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(raw.ends_with("Content-Length: 2\r\n\r\nok"));
+    }
+
+    #[tokio::test]
+    async fn stream_body_uses_chunked_encoding() {
+        // 每次最多读取8192字节，分为两块
+        let data = vec![b'x'; 10000];
+        let response = HttpResponse::new(HttpStatus::Ok, None, Body::stream(std::io::Cursor::new(data)));
+        let raw = write(response).await;
+        let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        let expected = format!("2000\r\n{}\r\n710\r\n{}\r\n0\r\n\r\n", "x".repeat(8192), "x".repeat(1808));
+        assert_eq!(body, expected);
+    }
+
+    #[tokio::test]
+    async fn stream_body_without_chunked_ends_with_close() {
+        let mut response = HttpResponse::new(HttpStatus::Ok, None, Body::stream(&b"hello"[..]));
+        response.disable_chunked();
+        let raw = write(response).await;
+        assert!(!raw.contains("Transfer-Encoding"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(raw.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn file_body_has_content_length() {
+        let path = std::env::temp_dir().join(format!("my-http-server-response-{}.txt", std::process::id()));
+        std::fs::write(&path, "file content").unwrap();
+        let mut response = HttpResponse::new(HttpStatus::Ok, None, Body::file(&path).await.unwrap());
+        response.set_header("Content-Length", "1");
+        let raw = write(response).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(!raw.contains("Transfer-Encoding"));
+        assert!(raw.ends_with("\r\nContent-Length: 12\r\n\r\nfile content"), "{}", raw);
+        assert_eq!(raw.matches("Content-Length").count(), 1);
     }
 }
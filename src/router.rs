@@ -1,19 +1,435 @@
-use crate::handler::HelloHandler;
-use crate::request::HttpRequest;
-use crate::response::HttpResponse;
-use super::handler::{Handler, StaticHandler};
+use std::collections::BTreeMap;
+use crate::error::{Error, Result};
+use crate::handler::Handler;
+use crate::middleware::{Endpoint, Middleware, Next};
+use crate::parser::RequestHead;
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::{HttpResponse, HttpStatus};
+use crate::state::State;
 
-pub struct Router;
+/// 路由中的一段路径
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// 普通路径，如 /users
+    Static(String),
+    /// 路径参数，如 /:id
+    Param(String),
+    /// 通配符，匹配剩余的全部路径，如 /*rest
+    Wildcard(String),
+}
+
+impl Segment {
+    /// 匹配优先级，数值越小越优先
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+    /// 形状相同的路由会产生冲突，如 /users/:id 和 /users/:name
+    fn same_shape(&self, other: &Segment) -> bool {
+        match (self, other) {
+            (Segment::Static(a), Segment::Static(b)) => a == b,
+            (Segment::Param(_), Segment::Param(_)) => true,
+            (Segment::Wildcard(_), Segment::Wildcard(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// 某个请求方法的处理器以及只作用于它的中间件
+struct MethodHandler {
+    method: HttpMethod,
+    handler: Box<dyn Handler>,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    pattern: String,
+    segments: Vec<Segment>,
+    // 每个请求方法对应的处理器
+    handlers: Vec<MethodHandler>,
+    // Allow响应头
+    allow: String,
+}
+
+impl Route {
+    fn handler(&self, method: &HttpMethod) -> Option<&MethodHandler> {
+        self.handlers.iter().find(|h| h.method == *method)
+    }
+    /// 根据已注册的方法生成Allow响应头，GET隐含HEAD，OPTIONS总是可用
+    fn update_allow(&mut self) {
+        let mut methods: Vec<&str> = self.handlers.iter().map(|h| h.method.as_str()).collect();
+        if self.handler(&HttpMethod::Get).is_some() && self.handler(&HttpMethod::Head).is_none() {
+            methods.push(HttpMethod::Head.as_str());
+        }
+        if self.handler(&HttpMethod::Options).is_none() {
+            methods.push(HttpMethod::Options.as_str());
+        }
+        self.allow = methods.join(", ");
+    }
+    /// 匹配路径，成功时返回提取的参数
+    fn matches(&self, path: &[&str]) -> Option<BTreeMap<String, String>> {
+        let mut params = BTreeMap::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(s) => {
+                    if path.get(i) != Some(&s.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let value = path.get(i).filter(|v| !v.is_empty())?;
+                    params.insert(name.clone(), value.to_string());
+                }
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), path.get(i..).unwrap_or_default().join("/"));
+                    return Some(params);
+                }
+            }
+        }
+        if path.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+    /// 用于比较优先级的排序键，逐段比较，普通路径 > 路径参数 > 通配符
+    fn precedence(&self) -> Vec<u8> {
+        self.segments.iter().map(Segment::rank).collect()
+    }
+}
+
+/// 路由表
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    // 全局中间件，在路由匹配之前执行
+    middlewares: Vec<Box<dyn Middleware>>,
+    // 处理器共享的应用状态
+    state: State,
+}
 
 impl Router {
-    pub fn route<'a>(req: HttpRequest) -> HttpResponse<'a> {
-        let route: Vec<&str> = req.url().split("/").collect();
-        match route[1] {
-            "hello" => {
-                HelloHandler::handle(&req)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 注册路由，支持 /users/:id 形式的路径参数和 /files/*rest 形式的通配符
+    pub fn add(&mut self, method: HttpMethod, pattern: &str, handler: impl Handler + 'static) -> Result<&mut Self> {
+        let handler = MethodHandler { method, handler: Box::new(handler), middlewares: Vec::new() };
+        let segments = parse_pattern(pattern)?;
+        let existing = self.routes.iter_mut().find(|r| {
+            r.segments.len() == segments.len()
+                && r.segments.iter().zip(&segments).all(|(a, b)| a.same_shape(b))
+        });
+        match existing {
+            // 同一路径的不同方法
+            Some(route) if route.pattern == pattern => {
+                if route.handler(&handler.method).is_some() {
+                    return Err(Error::invalid(format!("路由 {} {} 重复", handler.method.as_str(), pattern)));
+                }
+                route.handlers.push(handler);
+                route.update_allow();
+            }
+            Some(route) => {
+                return Err(Error::invalid(format!("路由 {} 与 {} 冲突", pattern, route.pattern)));
+            }
+            None => {
+                let mut route = Route {
+                    pattern: pattern.to_string(),
+                    segments,
+                    handlers: vec![handler],
+                    allow: String::new(),
+                };
+                route.update_allow();
+                self.routes.push(route);
             }
-            // 静态资源
-            _ => StaticHandler::handle(&req)
         }
+        Ok(self)
+    }
+    pub fn get(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<&mut Self> {
+        self.add(HttpMethod::Get, pattern, handler)
+    }
+    pub fn post(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<&mut Self> {
+        self.add(HttpMethod::Post, pattern, handler)
+    }
+    pub fn put(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<&mut Self> {
+        self.add(HttpMethod::Put, pattern, handler)
+    }
+    pub fn delete(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<&mut Self> {
+        self.add(HttpMethod::Delete, pattern, handler)
+    }
+    pub fn patch(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<&mut Self> {
+        self.add(HttpMethod::Patch, pattern, handler)
+    }
+    /// 添加全局中间件，按添加顺序由外到内执行，对所有请求生效，包括404和405
+    pub fn wrap(&mut self, middleware: impl Middleware + 'static) -> &mut Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+    /// 给已注册的路由添加中间件，在全局中间件之后、处理器之前按添加顺序执行
+    pub fn layer(&mut self, method: HttpMethod, pattern: &str, middleware: impl Middleware + 'static) -> Result<&mut Self> {
+        let handler = self.routes.iter_mut()
+            .filter(|r| r.pattern == pattern)
+            .find_map(|r| r.handlers.iter_mut().find(|h| h.method == method))
+            .ok_or_else(|| Error::invalid(format!("路由 {} {} 不存在", method.as_str(), pattern)))?;
+        handler.middlewares.push(Box::new(middleware));
+        Ok(self)
+    }
+    /// 添加应用状态，处理器通过[State::get]按类型获取
+    pub fn insert_state<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.state.insert(value);
+        self
+    }
+
+    /// 分发请求，依次经过全局中间件、路由匹配、路由中间件和处理器
+    pub async fn route(&self, req: HttpRequest<'_>) -> HttpResponse {
+        Next::new(&self.middlewares, Endpoint::Router(self), &self.state).run(req).await
+    }
+
+    /// 请求带有`Expect: 100-continue`时，由匹配的处理器决定是否接受请求体
+    /// 没有匹配的处理器时接受，读取请求体后再按正常流程响应
+    pub(crate) fn expect_continue(&self, head: &RequestHead) -> Option<HttpResponse> {
+        let method = HttpMethod::from(head.method());
+        let path = split_path(head.target().split('?').next().unwrap_or_default());
+        let (route, _) = self.routes.iter()
+            .filter_map(|r| r.matches(&path).map(|params| (r, params)))
+            .min_by_key(|(r, _)| r.precedence())?;
+        route.handler(&method)?.handler.expect_continue(head, &self.state)
+    }
+
+    /// 路由匹配
+    /// 路径存在但方法不匹配时返回405，HEAD使用GET的处理器，OPTIONS返回可用的方法，
+    /// 未实现的方法返回501
+    pub(crate) async fn dispatch<'a>(&'a self, mut req: HttpRequest<'a>) -> HttpResponse {
+        // 没有任何路由注册的非标准方法
+        if !req.method().is_implemented() && !self.routes.iter().any(|r| r.handler(req.method()).is_some()) {
+            return HttpResponse::new(HttpStatus::NotImplemented, None, None);
+        }
+        let path = split_path(req.url());
+        let matched = self.routes.iter()
+            .filter_map(|r| r.matches(&path).map(|params| (r, params)))
+            .min_by_key(|(r, _)| r.precedence());
+        let Some((route, params)) = matched else {
+            return HttpResponse::not_found(None);
+        };
+        req.set_params(params);
+        let handler = match route.handler(req.method()) {
+            Some(handler) => handler,
+            None => match (req.method(), route.handler(&HttpMethod::Get)) {
+                (HttpMethod::Head, Some(handler)) => handler,
+                (HttpMethod::Options, _) => {
+                    let mut response = HttpResponse::new(HttpStatus::Ok, None, None);
+                    response.set_header("Allow", route.allow.clone());
+                    return response;
+                }
+                _ => {
+                    let mut response = HttpResponse::new(HttpStatus::MethodNotAllowed, None, None);
+                    response.set_header("Allow", route.allow.clone());
+                    return response;
+                }
+            },
+        };
+        let endpoint = Endpoint::Handler(handler.handler.as_ref());
+        Next::new(&handler.middlewares, endpoint, &self.state).run(req).await
+    }
+}
+
+/// 按照 / 分割路径，去掉开头的 /
+fn split_path(path: &str) -> Vec<&str> {
+    path.strip_prefix('/').unwrap_or(path).split('/').collect()
+}
+
+/// 解析路由规则
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>> {
+    if !pattern.starts_with('/') {
+        return Err(Error::invalid(format!("路由 {} 必须以 / 开头", pattern)));
+    }
+    let parts = split_path(pattern);
+    let mut segments = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let segment = if let Some(name) = part.strip_prefix(':') {
+            Segment::Param(name.to_string())
+        } else if let Some(name) = part.strip_prefix('*') {
+            if i != parts.len() - 1 {
+                return Err(Error::invalid(format!("路由 {} 的通配符必须在最后", pattern)));
+            }
+            Segment::Wildcard(name.to_string())
+        } else {
+            Segment::Static(part.to_string())
+        };
+        match &segment {
+            Segment::Param(name) | Segment::Wildcard(name) => {
+                if name.is_empty() {
+                    return Err(Error::invalid(format!("路由 {} 的参数缺少名称", pattern)));
+                }
+                if segments.iter().any(|s| matches!(s, Segment::Param(n) | Segment::Wildcard(n) if n == name)) {
+                    return Err(Error::invalid(format!("路由 {} 的参数 {} 重复", pattern, name)));
+                }
+            }
+            Segment::Static(_) => {}
+        }
+        segments.push(segment);
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::handler::{handler_fn, Handler};
+    use crate::request::{HttpMethod, HttpRequest};
+    use crate::response::{Body, HttpResponse, HttpStatus};
+    use super::Router;
+
+    /// 以 key=value 的形式返回路径参数
+    fn echo() -> impl Handler {
+        handler_fn(|req, _| Box::pin(async move {
+            let body: Vec<String> = req.params().iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            HttpResponse::new(HttpStatus::Ok, None, Some(body.join("&").into_bytes()))
+        }))
+    }
+
+    fn me() -> impl Handler {
+        handler_fn(|_, _| Box::pin(async move {
+            HttpResponse::new(HttpStatus::Ok, None, Some(b"me".to_vec()))
+        }))
+    }
+
+    async fn dispatch(router: &Router, url: &str) -> (HttpStatus, String) {
+        request(router, "GET", url).await
+    }
+
+    async fn request(router: &Router, method: &str, url: &str) -> (HttpStatus, String) {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, url);
+        let req = HttpRequest::from(&raw, Vec::new(), "127.0.0.1").unwrap();
+        let response = router.route(req).await;
+        let body = match response.body() {
+            Body::Bytes(b) => String::from_utf8_lossy(b).to_string(),
+            _ => String::new(),
+        };
+        (response.status().clone(), body)
+    }
+
+    #[tokio::test]
+    async fn extracts_params_and_wildcards() {
+        let mut router = Router::new();
+        router.get("/users/:id", echo()).unwrap();
+        router.get("/files/*rest", echo()).unwrap();
+        assert_eq!(dispatch(&router, "/users/42").await.1, "id=42");
+        assert_eq!(dispatch(&router, "/files/a/b/c.txt").await.1, "rest=a/b/c.txt");
+        assert_eq!(dispatch(&router, "/users").await.0, HttpStatus::NotFound);
+        assert_eq!(dispatch(&router, "/users/").await.0, HttpStatus::NotFound);
+        assert_eq!(dispatch(&router, "/users/42/posts").await.0, HttpStatus::NotFound);
+    }
+
+    #[tokio::test]
+    async fn static_beats_param_beats_wildcard() {
+        let mut router = Router::new();
+        router.get("/*path", echo()).unwrap();
+        router.get("/users/:id", echo()).unwrap();
+        router.get("/users/me", me()).unwrap();
+        assert_eq!(dispatch(&router, "/users/me").await.1, "me");
+        assert_eq!(dispatch(&router, "/users/7").await.1, "id=7");
+        assert_eq!(dispatch(&router, "/users/7/posts").await.1, "path=users/7/posts");
+        assert_eq!(dispatch(&router, "/").await.1, "path=");
+    }
+
+    #[tokio::test]
+    async fn precedence_does_not_depend_on_registration_order() {
+        let mut router = Router::new();
+        router.get("/a/:x/c", echo()).unwrap();
+        router.get("/a/b/:y", echo()).unwrap();
+        // 第二段的普通路径优先于路径参数
+        assert_eq!(dispatch(&router, "/a/b/c").await.1, "y=c");
+    }
+
+    #[test]
+    fn rejects_conflicting_routes() {
+        let mut router = Router::new();
+        router.get("/users/:id", echo()).unwrap();
+        assert!(router.get("/users/:name", echo()).is_err());
+        assert!(router.get("/users/:id", me()).is_err());
+        router.get("/files/*rest", echo()).unwrap();
+        assert!(router.get("/files/*other", echo()).is_err());
+        // 不同形状的路由不冲突
+        router.get("/users/me", me()).unwrap();
+        router.get("/users/:id/posts", echo()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dispatches_by_method() {
+        let mut router = Router::new();
+        router.get("/users/:id", echo()).unwrap();
+        router.post("/users/:id", me()).unwrap();
+        assert_eq!(request(&router, "GET", "/users/1").await.1, "id=1");
+        assert_eq!(request(&router, "POST", "/users/1").await.1, "me");
+        // 同一路径同一方法重复注册
+        assert!(router.post("/users/:id", echo()).is_err());
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed_lists_allowed_methods() {
+        let mut router = Router::new();
+        router.get("/hello", me()).unwrap();
+        router.post("/hello", me()).unwrap();
+        let req = HttpRequest::from("DELETE /hello HTTP/1.1\r\n\r\n", Vec::new(), "127.0.0.1").unwrap();
+        let response = router.route(req).await;
+        assert_eq!(*response.status(), HttpStatus::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("GET, POST, HEAD, OPTIONS"));
+        let req = HttpRequest::from("OPTIONS /hello HTTP/1.1\r\n\r\n", Vec::new(), "127.0.0.1").unwrap();
+        let response = router.route(req).await;
+        assert_eq!(*response.status(), HttpStatus::Ok);
+        assert_eq!(response.header("Allow"), Some("GET, POST, HEAD, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn head_uses_get_handler() {
+        let mut router = Router::new();
+        router.get("/hello", me()).unwrap();
+        let (status, body) = request(&router, "HEAD", "/hello").await;
+        assert_eq!(status, HttpStatus::Ok);
+        // 响应体保留，由写出时跳过
+        assert_eq!(body, "me");
+    }
+
+    #[tokio::test]
+    async fn unsupported_methods() {
+        let mut router = Router::new();
+        router.get("/hello", me()).unwrap();
+        router.add(HttpMethod::Extension("PURGE".to_string()), "/cache", me()).unwrap();
+        assert_eq!(request(&router, "PUT", "/hello").await.0, HttpStatus::MethodNotAllowed);
+        assert_eq!(request(&router, "BREW", "/hello").await.0, HttpStatus::NotImplemented);
+        assert_eq!(request(&router, "TRACE", "/hello").await.0, HttpStatus::NotImplemented);
+        // 扩展方法只要有路由注册即可识别
+        assert_eq!(request(&router, "PURGE", "/cache").await.1, "me");
+        assert_eq!(request(&router, "PURGE", "/hello").await.0, HttpStatus::MethodNotAllowed);
+    }
+
+    #[tokio::test]
+    async fn handlers_share_state() {
+        struct Counter(AtomicUsize);
+        let mut router = Router::new();
+        router.insert_state(Counter(AtomicUsize::new(0)));
+        router.get("/count", handler_fn(|_, state| Box::pin(async move {
+            let counter = state.get::<Counter>().unwrap();
+            let count = counter.0.fetch_add(1, Ordering::SeqCst) + 1;
+            HttpResponse::new(HttpStatus::Ok, None, Some(count.to_string().into_bytes()))
+        }))).unwrap();
+        assert_eq!(dispatch(&router, "/count").await.1, "1");
+        assert_eq!(dispatch(&router, "/count").await.1, "2");
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let mut router = Router::new();
+        assert!(router.get("users", echo()).is_err());
+        assert!(router.get("/files/*rest/more", echo()).is_err());
+        assert!(router.get("/users/:", echo()).is_err());
+        assert!(router.get("/users/:id/:id", echo()).is_err());
     }
-}
\ No newline at end of file
+}
@@ -1,11 +1,25 @@
+use std::future::{pending, Future};
+use std::io;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use crate::error::{Fail, Result};
-use crate::request::HttpRequest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Sleep};
+use crate::error::{CatchUnwind, Error, ErrorPages, Result};
+use crate::http2::{self, Rewind, Upgrade};
+use crate::middleware::middleware_fn;
+use crate::multipart::{MultipartLimits, MultipartParser};
+use crate::parser::{Head, HeadParser, ParseError, RequestHead};
+use crate::request::{HttpMethod, HttpRequest, HttpVersion, RawBody};
 use crate::response::{HttpResponse, HttpStatus};
 use crate::router::Router;
+use crate::tls::{self, CertResolver, TlsSettings};
+use crate::utils::scan;
 
 #[derive(Clone, Debug)]
 pub struct HttpSettings {
@@ -13,11 +27,44 @@ pub struct HttpSettings {
     pub max_header_size: usize,
     /// 最大请求体大小
     pub max_body_size: usize,
+    /// multipart表单的最大大小，表单边读边解析，不受max_body_size限制
+    pub max_multipart_size: usize,
+    /// multipart表单中单个部分的最大大小
+    pub max_part_size: usize,
+    /// multipart表单中超过这个大小的部分写入临时文件
+    pub multipart_memory_limit: usize,
     /// 请求头读取
     pub header_buffer: usize,
     pub body_buffer: usize,
-    pub header_read_attempts: usize,
-    pub body_read_attempts: usize,
+    /// 收到请求的第一个字节后，完整的请求头需要在这个时间内收到，超时返回408
+    /// 新连接等待第一个请求的时间也受这个限制
+    pub header_timeout: Duration,
+    /// 读取请求体时两次收到数据之间的最长间隔，超时返回408
+    pub body_timeout: Duration,
+    /// 写出响应时连接无法写入的最长时间，超时后关闭连接
+    pub write_timeout: Duration,
+    /// 长连接等待下一个请求的空闲超时
+    pub keep_alive_timeout: Duration,
+    /// 严格按照RFC 9112验证请求，关闭时接受只以LF结束的行和多个相同的Content-Length
+    pub strict: bool,
+    /// 单个连接最多处理的请求数
+    pub max_keep_alive_requests: usize,
+    /// 流水线中最多排队等待写出的响应数
+    pub max_pipelined_requests: usize,
+    /// 是否支持HTTP/2，包括TLS的ALPN协商、明文的h2c升级和直接使用HTTP/2
+    pub http2: bool,
+    /// HTTP/2连接上同时处理的最大流数
+    pub max_concurrent_streams: u32,
+    /// 关闭服务器时等待进行中的请求完成的最长时间，超时后强制断开
+    pub shutdown_timeout: Duration,
+    /// 请求出错时的响应内容
+    pub error_pages: ErrorPages,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HttpSettings {
@@ -26,10 +73,31 @@ impl HttpSettings {
         Self {
             max_header_size: 8192, // 8kb
             max_body_size: 8192 * 1024, // 8mb
+            max_multipart_size: 64 * 1024 * 1024, // 64mb
+            max_part_size: 32 * 1024 * 1024, // 32mb
+            multipart_memory_limit: 256 * 1024, // 256kb
             header_buffer: 8192,
             body_buffer: 8192,
-            header_read_attempts: 3,
-            body_read_attempts: 3,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(5),
+            strict: true,
+            max_keep_alive_requests: 100,
+            max_pipelined_requests: 16,
+            http2: true,
+            max_concurrent_streams: 100,
+            shutdown_timeout: Duration::from_secs(30),
+            error_pages: ErrorPages::new(),
+        }
+    }
+    /// multipart表单的大小限制
+    pub fn multipart_limits(&self) -> MultipartLimits {
+        MultipartLimits {
+            max_part_size: self.max_part_size,
+            max_total_size: self.max_multipart_size,
+            memory_limit: self.multipart_memory_limit,
+            max_header_size: self.max_header_size,
         }
     }
 }
@@ -37,180 +105,1375 @@ impl HttpSettings {
 pub struct Server {
     socket_addr: SocketAddr,
     http_settings: Arc<HttpSettings>,
+    router: Arc<Router>,
+    // 启用HTTPS时的证书
+    tls: Option<(Arc<CertResolver>, Option<Duration>)>,
+    // 另外监听的HTTP端口，以及是否重定向到HTTPS
+    http: Option<(SocketAddr, bool)>,
 }
 
 impl Server {
     // 构造方法
-    pub fn new(addr: &str, http_settings: HttpSettings) -> Self {
+    pub fn new(addr: &str, http_settings: HttpSettings, router: Router) -> Self {
         let socket_addr = addr.parse().unwrap();
         let http_settings = Arc::new(http_settings);
-        Self { socket_addr, http_settings }
+        let router = Arc::new(router);
+        Self { socket_addr, http_settings, router, tls: None, http: None }
+    }
+
+    /// 主端口改为HTTPS，证书在这里加载，文件不存在或格式不对时返回错误
+    pub fn tls(mut self, tls_settings: TlsSettings) -> Result<Self> {
+        let resolver = CertResolver::new(&tls_settings)?;
+        self.tls = Some((resolver, tls_settings.reload_interval));
+        Ok(self)
+    }
+    /// 证书，可以调用[CertResolver::reload]手动重新加载
+    pub fn cert_resolver(&self) -> Option<Arc<CertResolver>> {
+        self.tls.as_ref().map(|(resolver, _)| resolver.clone())
+    }
+    /// 另外监听一个HTTP端口，`redirect`为true时所有请求重定向到HTTPS
+    pub fn http(mut self, addr: &str, redirect: bool) -> Self {
+        self.http = Some((addr.parse().unwrap(), redirect));
+        self
     }
 
-    // 运行
+    // 运行，直到进程退出
     pub async fn run(&self) -> Result<()> {
+        self.run_until(pending()).await
+    }
+
+    /// 运行，`signal`完成后停止接受新连接，等待进行中的请求完成后返回
+    /// 空闲的长连接立即关闭，超过[HttpSettings::shutdown_timeout]仍未完成的连接被强制断开
+    /// ```ignore
+    /// server.run_until(shutdown_signal()).await?;
+    /// ```
+    pub async fn run_until(&self, signal: impl Future<Output=()>) -> Result<()> {
         // 监听
         let conn_listener = TcpListener::bind(self.socket_addr).await?;
-        println!("Running on {}", self.socket_addr);
+        let mut http_listener = None;
+        if let Some((addr, redirect)) = self.http {
+            let router = match redirect {
+                true => Arc::new(redirect_router(self.socket_addr.port())),
+                false => self.router.clone(),
+            };
+            println!("Running on http://{}", addr);
+            http_listener = Some((TcpListener::bind(addr).await?, router));
+        }
+        let mut acceptor = None;
+        let mut watcher = None;
+        match &self.tls {
+            Some((resolver, reload_interval)) => {
+                if let Some(interval) = *reload_interval {
+                    watcher = Some(tokio::spawn(resolver.clone().watch(interval)));
+                }
+                acceptor = Some(tls::acceptor(resolver.clone(), self.http_settings.http2));
+                println!("Running on https://{}", self.socket_addr);
+            }
+            None => println!("Running on {}", self.socket_addr),
+        }
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let shutdown = Shutdown(shutdown);
+        let mut conns = JoinSet::new();
+        tokio::pin!(signal);
         loop {
-            // 处理每个连接
-            if let Ok((stream, address)) = conn_listener.accept().await {
-                let http_settings = self.http_settings.clone();
-                // 开启一个异步任务
-                tokio::spawn(async move {
-                    let mut stream = stream;
-                    match handle_conn(&http_settings, &mut stream, address).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            println!("{}", err);
-                            write_stream(&mut stream, HttpResponse::new(HttpStatus::BadRequest, None, Some(err.to_string().as_bytes().to_vec())).to_vec()).await;
+            tokio::select! {
+                _ = &mut signal => break,
+                // 处理每个连接
+                accepted = conn_listener.accept() => if let Ok((stream, address)) = accepted {
+                    let http_settings = self.http_settings.clone();
+                    let router = self.router.clone();
+                    let shutdown = shutdown.clone();
+                    match &acceptor {
+                        Some(acceptor) => {
+                            conns.spawn(accept_tls(acceptor.clone(), http_settings, router, stream, address, shutdown));
+                        }
+                        None => {
+                            conns.spawn(serve_conn(http_settings, router, stream, address, Protocol::Plain, shutdown));
                         }
-                    };
-                });
+                    }
+                },
+                accepted = accept_optional(&http_listener) => if let Ok((stream, address, router)) = accepted {
+                    let http_settings = self.http_settings.clone();
+                    conns.spawn(serve_conn(http_settings, router, stream, address, Protocol::Plain, shutdown.clone()));
+                },
+                // 回收已结束的连接
+                Some(_) = conns.join_next() => {}
             }
         }
+        // 停止接受新连接，通知所有连接
+        drop(conn_listener);
+        drop(http_listener);
+        if let Some(watcher) = watcher {
+            watcher.abort();
+        }
+        let _ = shutdown_tx.send(true);
+        println!("Shutting down, waiting for {} connections", conns.len());
+        let drained = timeout(self.http_settings.shutdown_timeout, async {
+            while conns.join_next().await.is_some() {}
+        }).await;
+        if drained.is_err() {
+            println!("Shutdown timed out, closing {} connections", conns.len());
+            conns.shutdown().await;
+        }
+        Ok(())
     }
 }
 
-async fn handle_conn(http_settings: &HttpSettings,
-                     mut stream: &mut TcpStream,
-                     addr: SocketAddr) -> Result<()> {
-    // 读取请求
-    let (header, mut body) = read_head(http_settings, &mut stream).await?;
-    let content_length = get_content_length(header.as_str());
-    if content_length > 0 {
-        read_body(&http_settings, &mut stream, &mut body, content_length).await?;
+/// 等待Ctrl-C，Unix上也等待SIGTERM，用于[Server::run_until]
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
-    let ip = addr.ip().to_string();
-    let request = HttpRequest::from(&header, body, &ip[..])?;
-    let response = Router::route(request);
-    write_stream(stream, response.to_vec()).await;
-    Ok(())
 }
 
-/// 响应数据
-async fn write_stream(stream: &mut TcpStream, content: Vec<u8>) {
-    match stream.write_all(&content).await {
-        Ok(_) => {
-            match stream.flush().await {
-                Ok(_) => {}
-                Err(err) => { println!("{}", err); }
-            }
+/// 接受另外监听的HTTP端口上的连接，没有时一直等待
+async fn accept_optional(listener: &Option<(TcpListener, Arc<Router>)>)
+                         -> std::io::Result<(TcpStream, SocketAddr, Arc<Router>)> {
+    match listener {
+        Some((listener, router)) => {
+            let (stream, address) = listener.accept().await?;
+            Ok((stream, address, router.clone()))
         }
-        Err(err) => { println!("{}", err); }
+        None => pending().await,
+    }
+}
+
+/// TLS握手后处理连接，握手也在连接的任务中进行，不阻塞其他连接
+async fn accept_tls(acceptor: tokio_rustls::TlsAcceptor,
+                    http_settings: Arc<HttpSettings>,
+                    router: Arc<Router>,
+                    stream: TcpStream,
+                    address: SocketAddr,
+                    mut shutdown: Shutdown) {
+    let stream = tokio::select! {
+        accepted = acceptor.accept(stream) => match accepted {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        },
+        // 关闭时放弃还未完成的握手
+        _ = shutdown.wait() => return,
+    };
+    let protocol = match stream.get_ref().1.alpn_protocol() {
+        Some(b"h2") => Protocol::Http2,
+        _ => Protocol::Tls,
     };
+    serve_conn(http_settings, router, stream, address, protocol, shutdown).await
 }
 
-/// 读取请求头
-async fn read_head(http_settings: &HttpSettings, stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
-    // 初始化缓存
-    let mut header = Vec::new();
-    let mut body = Vec::new();
-    let mut buf = vec![0u8; http_settings.header_buffer];
+/// 服务器正在关闭的通知
+#[derive(Clone)]
+pub(crate) struct Shutdown(watch::Receiver<bool>);
 
-    // 不停地读取流，直到读完请求头结束
-    let mut read_fails = 0;
-    'l: loop {
-        // 检查请求头是否超过限制
-        let length = stream.read(&mut buf).await?;
-        if header.len() + length > http_settings.max_header_size {
-            return Fail::from("请求头大小超出限制");
-        }
-        // 选择有效的切片
-        let buf = &buf[0..length];
-        // 遍历每一个字节
-        'f: for (i, &b) in buf.iter().enumerate() {
-            // 如果当前字节为'\r'
-            if b == b'\r' {
-                // 如果当前的缓冲区中剩余字节数不够3个
-                if buf.len() < i + 4 {
-                    // 从流中再次读取不够数量的字节
-                    let mut buf_temp = vec![0u8; i + 4 - buf.len()];
-                    stream.read_exact(&mut buf_temp).await?;
-                    // 合并缓冲区
-                    let mut buf2 = [buf, &buf_temp].concat();
-                    header.append(&mut buf2);
-                    // 检查请求头是否读取完毕 \n\r\n
-                    if buf2[i + 1] == b'\n' && buf2[i + 2] == b'\r' && buf2[i + 3] == b'\n' {
-                        break 'l;
-                    } else {
-                        break 'f;
-                    }
-                    // 如果当前的缓冲区中剩余字节数够读取3个，而且读到 \n\r\n
-                } else if buf[i + 1] == b'\n' && buf[i + 2] == b'\r' && buf[i + 3] == b'\n' {
-                    // 从结束的位置，分割请求头和请求体
-                    let (split1, split2) = buf.split_at(i + 4);
-                    header.extend_from_slice(split1);
-                    body.extend_from_slice(split2);
-                    break 'l;
+impl Shutdown {
+    /// 不会触发的通知
+    #[cfg(test)]
+    pub(crate) fn never() -> Self {
+        Shutdown(watch::channel(false).1)
+    }
+    pub(crate) fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+    /// 等待关闭，通知方已经不存在时一直等待
+    pub(crate) async fn wait(&mut self) {
+        if self.0.wait_for(|triggered| *triggered).await.is_err() {
+            pending::<()>().await;
+        }
+    }
+}
+
+/// 连接的字节流，明文的TcpStream或TLS流
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// 连接使用的协议
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    /// 明文连接，可以是HTTP/1.x，也可以直接使用或升级到HTTP/2
+    Plain,
+    /// TLS上的HTTP/1.x
+    Tls,
+    /// TLS的ALPN协商为h2
+    Http2,
+}
+
+/// 处理一个连接上的所有请求，出错时返回400
+pub(crate) async fn serve_conn(http_settings: Arc<HttpSettings>,
+                               router: Arc<Router>,
+                               stream: impl Stream,
+                               address: SocketAddr,
+                               protocol: Protocol,
+                               mut shutdown: Shutdown) {
+    let mut stream = WriteTimeout::new(stream, http_settings.write_timeout);
+    let mut buffered = Vec::new();
+    let h2c = protocol == Protocol::Plain && http_settings.http2;
+    let is_h2 = match protocol {
+        Protocol::Http2 => true,
+        _ if !h2c => false,
+        // 读取过程中被取消时，已经读到的字节仍在`buffered`中
+        _ => {
+            let preface = timeout(http_settings.header_timeout, http2::read_preface(&mut stream, &mut buffered));
+            let is_h2 = tokio::select! {
+                is_h2 = preface => is_h2.ok(),
+                _ = shutdown.wait() => None,
+            };
+            match is_h2 {
+                Some(is_h2) => is_h2,
+                // 还没有收到任何字节，直接关闭
+                None if buffered.is_empty() => {
+                    let _ = stream.shutdown().await;
+                    return;
                 }
+                // 序言只读到一部分，交给HTTP/1.x处理，由header_timeout限制
+                None => false,
+            }
+        }
+    };
+    if is_h2 {
+        let stream = Rewind::new(std::mem::take(&mut buffered), stream);
+        if let Err(err) = http2::serve(http_settings, router, stream, address, shutdown).await {
+            println!("{}", err);
+        }
+        return;
+    }
+    match handle_conn(&http_settings, &router, &mut stream, address, &mut buffered, h2c, &mut shutdown).await {
+        Ok(None) => {}
+        Ok(Some(upgrade)) => {
+            if let Err(err) = upgrade_h2c(http_settings, router, stream, address, buffered, upgrade, shutdown).await {
+                println!("{}", err);
+            }
+            return;
+        }
+        Err(err) => {
+            println!("{}", err);
+            // 连接已经无法读写时不再响应
+            if matches!(err, Error::Io(_)) {
+                return;
+            }
+            let mut response = http_settings.error_pages.render(&err);
+            response.set_header("Connection", "close");
+            if let Err(err) = write_stream(&mut stream, vec![response]).await {
+                println!("{}", err);
+                return;
+            }
+        }
+    };
+    // TLS连接关闭前需要发送close_notify
+    let _ = stream.shutdown().await;
+}
+
+/// 响应101之后切换到HTTP/2，升级的请求作为流1处理
+async fn upgrade_h2c(http_settings: Arc<HttpSettings>,
+                     router: Arc<Router>,
+                     mut stream: impl Stream,
+                     address: SocketAddr,
+                     mut buffered: Vec<u8>,
+                     upgrade: Upgrade,
+                     shutdown: Shutdown) -> Result<()> {
+    stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n").await?;
+    stream.flush().await?;
+    // 等待客户端的连接序言
+    let prefix = timeout(http_settings.header_timeout, http2::upgrade_prefix(&mut stream, &mut buffered, upgrade)).await
+        .map_err(|_| Error::timeout("等待HTTP/2连接序言超时"))??;
+    http2::serve(http_settings, router, Rewind::new(prefix, stream), address, shutdown).await
+}
+
+/// 所有请求重定向到HTTPS的同一地址
+fn redirect_router(https_port: u16) -> Router {
+    let mut router = Router::new();
+    router.wrap(middleware_fn(move |req, _, _| Box::pin(async move {
+        https_redirect(&req, https_port)
+    })));
+    router
+}
+
+fn https_redirect(req: &HttpRequest, https_port: u16) -> HttpResponse {
+    let Some(host) = req.headers().host() else {
+        return req.error_response(&Error::bad_request("缺少Host"));
+    };
+    // 去掉端口，IPv6地址在方括号中
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split_once(']').map(|(addr, _)| &host[..addr.len() + 2]).unwrap_or(host),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    let query = if req.query().is_empty() { String::new() } else { format!("?{}", req.query()) };
+    let location = format!("https://{}{}{}{}", host, port, req.url(), query);
+    let mut response = HttpResponse::new(HttpStatus::PermanentRedirect, None, None);
+    match response.try_set_header("Location", location) {
+        Ok(_) => response,
+        Err(err) => req.error_response(&Error::bad_request(err.to_string())),
+    }
+}
+
+/// 处理HTTP/1.x请求，`buffered`是上一个请求之后多读到的字节，流水线中的后续请求从这里继续解析
+/// 收到h2c升级请求时返回升级信息，此时之前的响应都已写出
+/// 服务器关闭时，空闲的连接直接关闭，正在处理的请求完成后关闭
+async fn handle_conn(http_settings: &HttpSettings,
+                     router: &Router,
+                     stream: &mut impl Stream,
+                     addr: SocketAddr,
+                     buffered: &mut Vec<u8>,
+                     h2c: bool,
+                     shutdown: &mut Shutdown) -> Result<Option<Upgrade>> {
+    let ip = addr.ip().to_string();
+    // 按请求顺序排队等待写出的响应
+    let mut pending = Vec::new();
+    let mut served = 0;
+    let result = loop {
+        // 缓冲区中已经没有完整的请求头，或排队的响应达到上限时，写出响应
+        if !pending.is_empty() && (scan(&buffered, b"\r\n\r\n").is_none()
+            || pending.len() >= http_settings.max_pipelined_requests) {
+            // 写出失败时已经写出了部分响应，不能再处理后面的请求
+            if let Err(err) = write_stream(stream, std::mem::take(&mut pending)).await {
+                break Err(err.into());
             }
-            // 没有读到 \r，而且缓冲区已经读完
-            if buf.len() == i + 1 {
-                // 将已经读取的内容存起来
-                header.extend_from_slice(buf);
+        }
+        match serve_one(http_settings, router, stream, &ip, buffered, &mut pending, served, h2c, shutdown).await {
+            Ok(Some(Served::Response(response, keep_alive))) => {
+                served += 1;
+                pending.push(response);
+                if !keep_alive {
+                    break Ok(None);
+                }
             }
+            Ok(Some(Served::Upgrade(upgrade))) => break Ok(Some(upgrade)),
+            // 客户端已关闭连接或空闲超时
+            Ok(None) => break Ok(None),
+            Err(err) => break Err(err),
+        }
+    };
+    // 连接已经无法读写时不再写出
+    if !pending.is_empty() && !matches!(result, Err(Error::Io(_))) {
+        write_stream(stream, pending).await?;
+    }
+    result
+}
+
+/// 一个请求的处理结果
+enum Served {
+    /// 响应以及是否保持连接
+    Response(HttpResponse, bool),
+    /// 升级到HTTP/2
+    Upgrade(Upgrade),
+}
+
+/// 读取并处理一个请求，连接已关闭、空闲超时或服务器关闭返回None
+/// `pending`是之前排队的响应，需要在100 Continue之前写出
+#[allow(clippy::too_many_arguments)]
+async fn serve_one(http_settings: &HttpSettings,
+                   router: &Router,
+                   stream: &mut impl Stream,
+                   ip: &str,
+                   buffered: &mut Vec<u8>,
+                   pending: &mut Vec<HttpResponse>,
+                   served: usize,
+                   h2c: bool,
+                   shutdown: &mut Shutdown) -> Result<Option<Served>> {
+    // 读取请求，除第一个请求外，等待时间受空闲超时限制
+    let idle = match served {
+        0 => http_settings.header_timeout,
+        _ => http_settings.keep_alive_timeout,
+    };
+    let Some((raw_head, head)) = wait_head(http_settings, stream, buffered, idle, shutdown).await? else {
+        return Ok(None);
+    };
+    let head = RequestHead::new(&raw_head, &head);
+    let framing = body_framing(http_settings, &head)?;
+    let mut sink = BodySink::new(http_settings, &head)?;
+    let has_body = !matches!(framing, BodyFraming::Length(0));
+    if let Some(mut response) = expect_continue(http_settings, router, stream, &head, &framing, &sink, buffered, pending).await? {
+        // 请求体没有读取，不能继续处理后面的请求
+        response.set_header("Connection", "close");
+        return Ok(Some(Served::Response(response, false)));
+    }
+    let trailers = match framing {
+        BodyFraming::Chunked => read_chunked_body(http_settings, stream, buffered, &mut sink).await?,
+        BodyFraming::Length(content_length) => {
+            read_body(http_settings, stream, buffered, content_length, &mut sink).await?;
+            Vec::new()
+        }
+    };
+    let mut request = HttpRequest::parse(head, sink.finish()?, ip, http_settings.multipart_limits())?;
+    request.set_trailers(trailers);
+    if h2c {
+        if let Some(upgrade) = http2::upgrade(&request, has_body) {
+            return Ok(Some(Served::Upgrade(upgrade)));
+        }
+    }
+    let mut keep_alive = request.keep_alive() && served + 1 < http_settings.max_keep_alive_requests;
+    let http_1_0 = *request.version() == HttpVersion::V1_0;
+    let head = *request.method() == HttpMethod::Head;
+    let mut response = respond(http_settings, router, request).await;
+    // HEAD的响应头与GET相同，但不写出响应体
+    if head {
+        response.set_head_only();
+    }
+    // HTTP/1.0不支持分块编码，长度未知的响应体写完后关闭连接
+    if http_1_0 && response.body().len().is_none() {
+        response.disable_chunked();
+        keep_alive = false;
+    }
+    // 处理期间服务器开始关闭，响应后关闭连接
+    keep_alive &= !shutdown.is_triggered();
+    response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    Ok(Some(Served::Response(response, keep_alive)))
+}
+
+/// 路由处理请求，处理器panic时记录日志并返回500，不影响连接上的其他请求
+pub(crate) async fn respond(http_settings: &HttpSettings, router: &Router, mut request: HttpRequest<'_>) -> HttpResponse {
+    // 中间件和处理器通过[HttpRequest::error_response]使用同样的错误页面
+    request.extensions_mut().insert(http_settings.error_pages.clone());
+    match CatchUnwind::new(router.route(request)).await {
+        Ok(response) => response,
+        Err(err) => {
+            println!("{}", err);
+            http_settings.error_pages.render(&err)
+        }
+    }
+}
+
+/// 按顺序写出响应，失败时连接上可能已经写出了部分响应，调用者需要关闭连接
+async fn write_stream(stream: &mut impl Stream, responses: Vec<HttpResponse>) -> io::Result<()> {
+    let mut writer = BufWriter::new(stream);
+    for response in responses {
+        response.write_to(&mut writer).await?;
+    }
+    writer.flush().await
+}
+
+/// 等待下一个请求并读取请求头，最多等待`idle`收到第一个字节，之后的请求头受header_timeout限制
+/// 没有收到任何字节时连接关闭、等待超时或服务器关闭，返回None
+async fn wait_head(http_settings: &HttpSettings,
+                   stream: &mut impl Stream,
+                   buffered: &mut Vec<u8>,
+                   idle: Duration,
+                   shutdown: &mut Shutdown) -> Result<Option<(String, Head)>> {
+    if buffered.is_empty() {
+        if shutdown.is_triggered() {
+            return Ok(None);
         }
-        // 如果没有读取完毕，重试
-        if length < http_settings.header_buffer {
-            read_fails += 1;
-            // 超出重试次数返回错误信息
-            if read_fails > http_settings.header_read_attempts {
-                return Fail::from("读取请求头失败");
+        let mut buf = vec![0u8; http_settings.header_buffer];
+        let read = tokio::select! {
+            read = timeout(idle, stream.read(&mut buf)) => read,
+            _ = shutdown.wait() => return Ok(None),
+        };
+        match read {
+            Ok(Ok(length)) if length > 0 => buffered.extend_from_slice(&buf[..length]),
+            // 连接已关闭或空闲超时，直接关闭，不需要响应
+            _ => return Ok(None),
+        }
+    }
+    match timeout(http_settings.header_timeout, read_head(http_settings, stream, buffered)).await {
+        Ok(head) => head,
+        Err(_) => Err(Error::timeout("读取请求头超时")),
+    }
+}
+
+/// 读取请求头，请求头之后已经读到的字节保留在`buffered`中
+/// 连接在读到任何数据之前被关闭时返回None
+async fn read_head(http_settings: &HttpSettings,
+                   stream: &mut impl Stream,
+                   buffered: &mut Vec<u8>) -> Result<Option<(String, Head)>> {
+    let mut buf = vec![0u8; http_settings.header_buffer];
+    // 每次读到数据后从上次停下的位置继续解析
+    let mut parser = HeadParser::new(http_settings.max_header_size).strict(http_settings.strict);
+    loop {
+        if let Some(head) = parser.parse(buffered)? {
+            // 从结束的位置，分割请求头和请求体
+            let rest = buffered.split_off(head.size());
+            let raw = std::mem::replace(buffered, rest);
+            // 只有头的值可能包含非ASCII字符
+            let raw = String::from_utf8(raw).map_err(|_| ParseError::InvalidHeaderValue)?;
+            return Ok(Some((raw, head)));
+        }
+        let length = stream.read(&mut buf).await?;
+        if length == 0 {
+            if buffered.is_empty() {
+                return Ok(None);
             }
+            return Err(Error::bad_request("读取请求头失败"));
+        }
+        buffered.extend_from_slice(&buf[..length]);
+    }
+}
+
+/// 处理`Expect: 100-continue`，在读取请求体之前检查大小限制和处理器的决定
+/// 拒绝时返回最终响应，接受时写出100 Continue，客户端已经开始发送请求体时不需要
+#[allow(clippy::too_many_arguments)]
+async fn expect_continue(http_settings: &HttpSettings,
+                         router: &Router,
+                         stream: &mut impl Stream,
+                         head: &RequestHead<'_>,
+                         framing: &BodyFraming,
+                         sink: &BodySink,
+                         buffered: &[u8],
+                         pending: &mut Vec<HttpResponse>) -> Result<Option<HttpResponse>> {
+    // HTTP/1.0的客户端不理解100 Continue，忽略Expect
+    let Some(expect) = head.header("expect").filter(|_| head.version() == "HTTP/1.1") else {
+        return Ok(None);
+    };
+    if !expect.eq_ignore_ascii_case("100-continue") {
+        return Ok(Some(HttpResponse::new(HttpStatus::ExpectationFailed, None, None)));
+    }
+    match framing {
+        BodyFraming::Length(0) => return Ok(None),
+        BodyFraming::Length(len) if *len > sink.limit(http_settings) => {
+            return Ok(Some(http_settings.error_pages.render(&Error::too_large("请求体大小超出限制"))));
+        }
+        _ => {}
+    }
+    if let Some(response) = router.expect_continue(head) {
+        return Ok(Some(response));
+    }
+    if buffered.is_empty() {
+        if !pending.is_empty() {
+            write_stream(stream, std::mem::take(pending)).await?;
         }
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        stream.flush().await?;
+    }
+    Ok(None)
+}
+
+/// 请求体的长度确定方式
+enum BodyFraming {
+    /// 由Content-Length指定长度
+    Length(usize),
+    /// Transfer-Encoding: chunked
+    Chunked,
+}
+
+/// 检查请求头并获取请求体的长度确定方式
+fn body_framing(http_settings: &HttpSettings, head: &RequestHead) -> Result<BodyFraming> {
+    head.validate(http_settings.strict)?;
+    if head.is_chunked() {
+        return Ok(BodyFraming::Chunked);
     }
-    // 将请求头转为utf8字符串
-    Ok((String::from_utf8(header)?, body))
+    Ok(BodyFraming::Length(head.content_length().unwrap_or_default()))
+}
+
+/// 读取到的请求体数据的去处
+pub(crate) enum BodySink {
+    /// 保存在内存中，受max_body_size限制
+    Bytes(Vec<u8>),
+    /// multipart表单边读边解析，大的部分写入临时文件
+    Multipart(MultipartParser),
 }
 
-/// 从请求头获取Content-Length
-fn get_content_length(head: &str) -> usize {
-    let mut size: usize = 0;
-    for hl in head.lines() {
-        let mut split_hl = hl.splitn(2, ":");
-        if let (Some(key), Some(value)) = (split_hl.next(), split_hl.next()) {
-            if key.trim().to_lowercase().eq("content-length") {
-                size = match value.parse::<usize>() {
-                    Ok(s) => s,
-                    Err(_) => 0
+impl BodySink {
+    pub(crate) fn new(http_settings: &HttpSettings, head: &RequestHead) -> Result<Self> {
+        let parser = match head.header("content-type") {
+            Some(content_type) => MultipartParser::from_content_type(content_type, http_settings.multipart_limits())?,
+            None => None,
+        };
+        Ok(parser.map_or_else(|| BodySink::Bytes(Vec::new()), BodySink::Multipart))
+    }
+    /// 请求体的最大大小
+    pub(crate) fn limit(&self, http_settings: &HttpSettings) -> usize {
+        match self {
+            BodySink::Bytes(_) => http_settings.max_body_size,
+            BodySink::Multipart(_) => http_settings.max_multipart_size,
+        }
+    }
+    pub(crate) async fn write(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            BodySink::Bytes(body) => {
+                body.extend_from_slice(data);
+                Ok(())
+            }
+            // 写入临时文件会阻塞，放到阻塞线程中执行
+            BodySink::Multipart(parser) if parser.may_spill(data.len()) => {
+                let BodySink::Multipart(mut parser) = std::mem::replace(self, BodySink::Bytes(Vec::new())) else {
+                    unreachable!()
                 };
+                let data = data.to_vec();
+                let parser = tokio::task::spawn_blocking(move || parser.feed(&data).map(|_| parser)).await
+                    .map_err(Error::other)??;
+                *self = BodySink::Multipart(parser);
+                Ok(())
             }
+            BodySink::Multipart(parser) => parser.feed(data),
+        }
+    }
+    pub(crate) fn finish(self) -> Result<RawBody> {
+        match self {
+            BodySink::Bytes(body) => Ok(RawBody::Bytes(body)),
+            BodySink::Multipart(parser) => Ok(RawBody::Multipart(parser.finish()?)),
         }
     }
-    size
 }
 
-/// 读取完整的body
+/// 读取完整的body写入`sink`，超出Content-Length的部分属于下一个请求，保留在`buffered`中
 async fn read_body(http_settings: &HttpSettings,
-                   stream: &mut TcpStream,
-                   body: &mut Vec<u8>,
-                   content_len: usize) -> Result<()> {
-    if content_len > http_settings.max_body_size {
-        return Err(Fail::new("请求体大小超出限制"));
-    }
-    let mut read_fails = 0;
-    while body.len() < content_len {
-        // 计算剩下的大小
-        let rest_len = content_len - body.len();
-        let buf_len = if rest_len > http_settings.body_buffer {
-            http_settings.body_buffer
-        } else { rest_len };
-        let mut buf = vec![0u8; buf_len];
-        let length = match stream.read(&mut buf).await {
-            Ok(len) => { len }
-            Err(_) => { return Err(Fail::new("请求体读取失败")); }
+                   stream: &mut impl Stream,
+                   buffered: &mut Vec<u8>,
+                   content_len: usize,
+                   sink: &mut BodySink) -> Result<()> {
+    if content_len > sink.limit(http_settings) {
+        return Err(Error::too_large("请求体大小超出限制"));
+    }
+    copy_body(http_settings, stream, buffered, content_len, sink).await
+}
+
+/// 从`buffered`和流中取出`len`个字节写入`sink`，边读边写
+async fn copy_body(http_settings: &HttpSettings,
+                   stream: &mut impl Stream,
+                   buffered: &mut Vec<u8>,
+                   len: usize,
+                   sink: &mut BodySink) -> Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        if buffered.is_empty() {
+            fill_buffer(http_settings, stream, buffered, 1).await?;
+        }
+        let len = remaining.min(buffered.len());
+        sink.write(&buffered[..len]).await?;
+        buffered.drain(..len);
+        remaining -= len;
+    }
+    Ok(())
+}
+
+/// 读取分块编码的请求体，解码后的数据写入`sink`，返回尾部字段
+/// 请求体之后已经读到的字节保留在`buffered`中
+async fn read_chunked_body(http_settings: &HttpSettings,
+                           stream: &mut impl Stream,
+                           buffered: &mut Vec<u8>,
+                           sink: &mut BodySink) -> Result<Vec<(String, String)>> {
+    let limit = sink.limit(http_settings);
+    let mut body_size: usize = 0;
+    loop {
+        // 块大小行 chunk-size [ ; chunk-ext ] CRLF
+        let line_end = fill_line(http_settings, stream, buffered).await?;
+        let line = std::str::from_utf8(&buffered[..line_end])
+            .map_err(|_| Error::bad_request("无效的分块大小"))?;
+        let size = parse_chunk_size(line).ok_or_else(|| Error::bad_request("无效的分块大小"))?;
+        buffered.drain(..line_end + 2);
+        // 最后一个块
+        if size == 0 {
+            break;
+        }
+        body_size = body_size.saturating_add(size);
+        if body_size > limit {
+            return Err(Error::too_large("请求体大小超出限制"));
+        }
+        copy_body(http_settings, stream, buffered, size, sink).await?;
+        // 块数据后面跟着 CRLF
+        fill_buffer(http_settings, stream, buffered, 2).await?;
+        if &buffered[..2] != b"\r\n" {
+            return Err(Error::bad_request("分块数据损坏"));
+        }
+        buffered.drain(..2);
+    }
+    // 尾部字段，以空行结束
+    let mut trailers = Vec::new();
+    let mut trailers_size = 0;
+    loop {
+        let line_end = fill_line(http_settings, stream, buffered).await?;
+        if line_end == 0 {
+            buffered.drain(..2);
+            break;
+        }
+        trailers_size += line_end + 2;
+        if trailers_size > http_settings.max_header_size {
+            return Err(Error::too_large("尾部字段大小超出限制"));
+        }
+        let line = std::str::from_utf8(&buffered[..line_end])
+            .map_err(|_| Error::bad_request("无效的尾部字段"))?;
+        let (key, value) = line.split_once(':')
+            .ok_or_else(|| Error::bad_request("无效的尾部字段"))?;
+        trailers.push((key.trim().to_lowercase(), value.trim().to_string()));
+        buffered.drain(..line_end + 2);
+    }
+    Ok(trailers)
+}
+
+/// chunk-size = 1*HEXDIG，后面直接是 ; 或行尾
+/// 不接受前后的空白，不同实现对空白的处理不一致，可能被用于请求走私
+fn parse_chunk_size(line: &str) -> Option<usize> {
+    let end = line.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(line.len());
+    if end == 0 || (end < line.len() && !line[end..].starts_with(';')) {
+        return None;
+    }
+    usize::from_str_radix(&line[..end], 16).ok()
+}
+
+/// 确保`buffered`中有完整的一行，返回行尾 CRLF 的位置
+async fn fill_line(http_settings: &HttpSettings,
+                   stream: &mut impl Stream,
+                   buffered: &mut Vec<u8>) -> Result<usize> {
+    loop {
+        if let Some(pos) = scan(&buffered[..], b"\r\n") {
+            return Ok(pos);
+        }
+        if buffered.len() > http_settings.max_header_size {
+            return Err(Error::bad_request("分块数据损坏"));
+        }
+        fill_buffer(http_settings, stream, buffered, buffered.len() + 1).await?;
+    }
+}
+
+/// 从流中读取数据，直到`buffered`中至少有`len`个字节，每次读取受body_timeout限制
+async fn fill_buffer(http_settings: &HttpSettings,
+                     stream: &mut impl Stream,
+                     buffered: &mut Vec<u8>,
+                     len: usize) -> Result<()> {
+    let mut buf = vec![0u8; http_settings.body_buffer];
+    while buffered.len() < len {
+        let length = match timeout(http_settings.body_timeout, stream.read(&mut buf)).await {
+            Err(_) => return Err(Error::timeout("读取请求体超时")),
+            Ok(Ok(0)) | Ok(Err(_)) => return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "请求体读取失败"))),
+            Ok(Ok(len)) => len,
         };
-        buf.truncate(length);
         // 追加
-        body.append(&mut buf);
-        // 最多读取次数
-        if length < http_settings.body_buffer {
-            read_fails += 1;
-            if read_fails > http_settings.body_read_attempts {
-                return Fail::from("请求体读取失败");
+        buffered.extend_from_slice(&buf[..length]);
+    }
+    Ok(())
+}
+
+/// 写入时连接长时间无法写入则返回TimedOut错误，防止客户端不读取响应而占用连接
+pub(crate) struct WriteTimeout<S> {
+    inner: S,
+    timeout: Duration,
+    // 写入开始等待时启动的计时器
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> WriteTimeout<S> {
+    pub(crate) fn new(inner: S, timeout: Duration) -> Self {
+        Self { inner, timeout, sleep: None }
+    }
+
+    fn poll_write_op<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.sleep = None;
+            return poll;
+        }
+        let timeout = self.timeout;
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.sleep = None;
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "写出响应超时")))
             }
+            Poll::Pending => Poll::Pending,
         }
     }
-    Ok(())
-}
\ No newline at end of file
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WriteTimeout<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.poll_write_op(cx, poll)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.poll_write_op(cx, poll)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.poll_write_op(cx, poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use crate::error::{ErrorPages, Result};
+    use crate::handler::{handler_fn, BoxFuture, Handler};
+    use crate::parser::RequestHead;
+    use crate::request::HttpRequest;
+    use crate::state::State;
+    use crate::response::{Body, HttpResponse, HttpStatus};
+    use crate::router::Router;
+    use tokio::time::timeout;
+    use super::{redirect_router, HttpSettings, Server};
+
+    /// 正在执行的/guarded处理器数，处理器被取消时减少
+    static LIVE_HANDLERS: AtomicUsize = AtomicUsize::new(0);
+
+    /// /counted处理器被调用的次数
+    static COUNTED: AtomicUsize = AtomicUsize::new(0);
+
+    struct LiveHandler;
+
+    impl LiveHandler {
+        fn new() -> Self {
+            LIVE_HANDLERS.fetch_add(1, Ordering::SeqCst);
+            LiveHandler
+        }
+    }
+
+    impl Drop for LiveHandler {
+        fn drop(&mut self) {
+            LIVE_HANDLERS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// 没有上传权限时在发送请求体之前拒绝
+    struct Upload;
+
+    impl Handler for Upload {
+        fn handle<'a>(&'a self, req: &'a HttpRequest<'a>, _state: &'a State) -> BoxFuture<'a, HttpResponse> {
+            let body = req.body().get("__raw").cloned().unwrap_or_default();
+            Box::pin(async move { HttpResponse::new(HttpStatus::Created, None, Some(body)) })
+        }
+        fn expect_continue(&self, head: &RequestHead, _state: &State) -> Option<HttpResponse> {
+            match head.header("authorization") {
+                Some(_) => None,
+                None => Some(HttpResponse::new(HttpStatus::Unauthorized, None, None)),
+            }
+        }
+    }
+
+    /// 启动服务器，/slow 在指定时间后响应，发送返回值后开始关闭
+    async fn start(delay: Duration, http_settings: HttpSettings) -> (String, oneshot::Sender<()>, JoinHandle<Result<()>>) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut router = Router::new();
+        router.get("/slow", handler_fn(move |_, _| Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            HttpResponse::new(HttpStatus::Ok, None, Some(b"done".to_vec()))
+        }))).unwrap();
+        router.get("/big", handler_fn(|_, _| Box::pin(async move {
+            HttpResponse::new(HttpStatus::Ok, None, Some(vec![b'x'; 64 * 1024 * 1024]))
+        }))).unwrap();
+        router.get("/counted", handler_fn(|_, _| Box::pin(async move {
+            COUNTED.fetch_add(1, Ordering::SeqCst);
+            HttpResponse::new(HttpStatus::Ok, None, None)
+        }))).unwrap();
+        router.post("/echo", handler_fn(|req, _| Box::pin(async move {
+            let body = req.body().get("__raw").cloned().unwrap_or_default();
+            HttpResponse::new(HttpStatus::Ok, None, Some(body))
+        }))).unwrap();
+        router.get("/panic", handler_fn(|_, _| Box::pin(async move {
+            panic!("处理器出错");
+        }))).unwrap();
+        router.put("/upload", Upload).unwrap();
+        router.get("/guarded", handler_fn(|_, _| Box::pin(async move {
+            let _guard = LiveHandler::new();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            HttpResponse::new(HttpStatus::Ok, None, None)
+        }))).unwrap();
+        router.post("/form", handler_fn(|req, _| Box::pin(async move {
+            let parts: Vec<String> = req.parts().iter()
+                .map(|p| format!("{}={}:{}", p.name(), String::from_utf8_lossy(&p.read_all().unwrap()), p.path().is_some()))
+                .collect();
+            HttpResponse::new(HttpStatus::Ok, None, Some(parts.join(",").into_bytes()))
+        }))).unwrap();
+        router.get("/stream", handler_fn(|_, _| Box::pin(async move {
+            HttpResponse::new(HttpStatus::Ok, None, Body::stream(&b"streamed"[..]))
+        }))).unwrap();
+        router.post("/trailers", handler_fn(|req, _| Box::pin(async move {
+            let body = req.body().get("__raw").cloned().unwrap_or_default();
+            let trailers: Vec<String> = req.trailers().iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            let body = format!("{}|{}", String::from_utf8_lossy(&body), trailers.join(","));
+            HttpResponse::new(HttpStatus::Ok, None, Some(body.into_bytes()))
+        }))).unwrap();
+        let server = Server::new(&addr, http_settings, router);
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            server.run_until(async { let _ = rx.await; }).await
+        });
+        // 等待开始监听
+        for _ in 0..100 {
+            if TcpStream::connect(&addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        (addr, tx, handle)
+    }
+
+    async fn redirect(raw: &str, https_port: u16) -> HttpResponse {
+        let mut req = HttpRequest::from(raw, Vec::new(), "127.0.0.1").unwrap();
+        req.extensions_mut().insert(ErrorPages::new().json());
+        redirect_router(https_port).route(req).await
+    }
+
+    #[tokio::test]
+    async fn redirects_to_https() {
+        let cases = [
+            ("GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n", 8443, "https://example.com:8443/a"),
+            ("GET /a HTTP/1.1\r\nHost: example.com:8080\r\n\r\n", 443, "https://example.com/a"),
+            ("GET /a HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n", 8443, "https://[::1]:8443/a"),
+            ("GET /a/b?x=1&y=2 HTTP/1.1\r\nHost: example.com\r\n\r\n", 443, "https://example.com/a/b?x=1&y=2"),
+        ];
+        for (raw, https_port, location) in cases {
+            let response = redirect(raw, https_port).await;
+            assert_eq!(response.status(), &HttpStatus::PermanentRedirect);
+            assert_eq!(response.header("location"), Some(location));
+        }
+        // 没有Host时使用错误页面
+        let response = redirect("GET /a HTTP/1.0\r\n\r\n", 443).await;
+        assert_eq!(response.status(), &HttpStatus::BadRequest);
+        assert_eq!(response.header("location"), None);
+        assert!(matches!(response.body(), Body::Bytes(body) if body == r#"{"code":400,"msg":"缺少Host"}"#.as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn drains_in_flight_requests() {
+        let (addr, tx, handle) = start(Duration::from_millis(300), HttpSettings::new()).await;
+        let mut busy = TcpStream::connect(&addr).await.unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        // 空闲的长连接在关闭时直接断开
+        let mut idle = TcpStream::connect(&addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(()).unwrap();
+
+        let mut response = String::new();
+        busy.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\ndone"));
+        assert_eq!(idle.read(&mut [0u8; 16]).await.unwrap(), 0);
+        handle.await.unwrap().unwrap();
+        assert!(TcpStream::connect(&addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_after_deadline() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.shutdown_timeout = Duration::from_millis(100);
+        let (addr, tx, handle) = start(Duration::from_secs(30), http_settings).await;
+        let mut busy = TcpStream::connect(&addr).await.unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        tx.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(busy.read(&mut [0u8; 16]).await.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn http2_streams_finish_after_goaway() {
+        let (addr, tx, handle) = start(Duration::from_millis(300), HttpSettings::new()).await;
+        let (client, conn) = h2::client::handshake(TcpStream::connect(&addr).await.unwrap()).await.unwrap();
+        let conn = tokio::spawn(conn);
+        let mut client = client.ready().await.unwrap();
+        let request = http::Request::get(format!("http://{}/slow", addr)).body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(()).unwrap();
+
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), 200);
+        let mut body = response.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap(), "done");
+        drop(client);
+        // 流结束后连接关闭
+        conn.await.unwrap().unwrap();
+        timeout(Duration::from_secs(5), handle).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn http2_streams_cancelled_after_deadline() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.shutdown_timeout = Duration::from_millis(100);
+        let (addr, tx, handle) = start(Duration::ZERO, http_settings).await;
+        let (client, conn) = h2::client::handshake(TcpStream::connect(&addr).await.unwrap()).await.unwrap();
+        tokio::spawn(conn);
+        let mut client = client.ready().await.unwrap();
+        let request = http::Request::get(format!("http://{}/guarded", addr)).body(()).unwrap();
+        let (_response, _) = client.send_request(request, true).unwrap();
+        while LIVE_HANDLERS.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tx.send(()).unwrap();
+        timeout(Duration::from_secs(5), handle).await.unwrap().unwrap().unwrap();
+        // 超时后流的处理器随连接一起取消，不会在服务器关闭后继续运行
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(LIVE_HANDLERS.load(Ordering::SeqCst), 0);
+    }
+
+    fn short_timeouts() -> HttpSettings {
+        let mut http_settings = HttpSettings::new();
+        http_settings.header_timeout = Duration::from_millis(200);
+        http_settings.body_timeout = Duration::from_millis(200);
+        http_settings.write_timeout = Duration::from_millis(200);
+        http_settings.keep_alive_timeout = Duration::from_millis(200);
+        http_settings
+    }
+
+    #[tokio::test]
+    async fn slow_clients_within_timeouts() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, short_timeouts()).await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        // 每次只发送几个字节，之前按读取次数判断会失败
+        let request = b"POST /echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 10\r\n\r\n0123456789";
+        for piece in request.chunks(7) {
+            client.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n0123456789"));
+    }
+
+    #[tokio::test]
+    async fn request_timeouts() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, short_timeouts()).await;
+        // 请求头不完整
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\nHost: loc").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        // 请求体不完整
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n01234").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        // 没有发送任何数据的连接直接关闭
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        // 空闲的长连接直接关闭
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.ends_with(b"\r\n\r\ndone"));
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_stop_reading() {
+        let (addr, tx, handle) = start(Duration::ZERO, short_timeouts()).await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        // 不读取响应，写出超时后连接关闭，关闭服务器时不需要等待
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        tx.send(()).unwrap();
+        timeout(Duration::from_secs(5), handle).await.unwrap().unwrap().unwrap();
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn stalled_writes_close_connection() {
+        let mut http_settings = short_timeouts();
+        http_settings.keep_alive_timeout = Duration::from_secs(5);
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"GET /big HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        // 写出超时后再发送请求，不能在写出了一半的响应之后继续处理
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let _ = client.write_all(b"GET /counted HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(COUNTED.load(Ordering::SeqCst), 0);
+        // 读完已经写出的部分之后连接关闭
+        let mut response = Vec::new();
+        let _ = timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await.unwrap();
+        assert!(response.len() < 64 * 1024 * 1024);
+        assert_eq!(COUNTED.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_ambiguous_requests() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, HttpSettings::new()).await;
+        let cases = [
+            ("Host: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n", "Content-Length的值不一致"),
+            ("Host: a\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n", "Transfer-Encoding和Content-Length不能同时存在"),
+            ("Host: a\r\nContent-Length: -5\r\n", "无效的Content-Length"),
+            ("Host: a\r\nX-A: 1\r\n b\r\n", "不支持多行的头(obs-fold)"),
+            ("Host: a\r\nContent-Length : 5\r\n", "头名称和冒号之间不能有空白"),
+            ("Host: a\nContent-Length: 5\r\n", "行必须以CRLF结束"),
+            ("Content-Length: 5\r\n", "HTTP/1.1请求缺少Host"),
+        ];
+        for (headers, reason) in cases {
+            // 后面的请求不能被当作新的请求处理
+            let request = format!("POST /echo HTTP/1.1\r\n{}\r\nhello GET /slow HTTP/1.1\r\nHost: a\r\n\r\n", headers);
+            let mut client = TcpStream::connect(&addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+            assert!(response.contains("Connection: close\r\n"));
+            assert!(response.ends_with(reason), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_versions() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, HttpSettings::new()).await;
+        for version in ["HTTP/2.0", "HTTP/3.0", "HTTP/0.9"] {
+            let mut client = TcpStream::connect(&addr).await.unwrap();
+            client.write_all(format!("GET /slow {}\r\nHost: a\r\n\r\n", version).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"), "{}", response);
+        }
+        // 更高的次版本按HTTP/1.1处理
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"GET /slow HTTP/1.2\r\nHost: a\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn handler_panics_become_500() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, HttpSettings::new()).await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        // panic之后连接上的后续请求正常处理
+        client.write_all(b"GET /panic HTTP/1.1\r\nHost: a\r\n\r\nGET /slow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"), "{}", response);
+        // 不返回panic的内容
+        assert!(!response.contains("处理器出错"));
+        assert!(response.contains("\r\n\r\nInternal Server ErrorHTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\ndone"));
+    }
+
+    #[tokio::test]
+    async fn renders_error_pages() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.max_body_size = 4;
+        http_settings.error_pages = ErrorPages::new().json().page(HttpStatus::InternalServerError, "<h1>出错了</h1>");
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        let cases = [
+            ("GET /panic HTTP/1.1\r\nHost: a\r\n\r\n", "500 Internal Server Error", "<h1>出错了</h1>"),
+            ("POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello",
+             "413 Content Too Large", r#"{"code":413,"msg":"请求体大小超出限制"}"#),
+            ("GET /slow HTTP/1.1\r\nContent-Length: 5\r\n\r\n", "400 Bad Request", r#"{"code":400,"msg":"HTTP/1.1请求缺少Host"}"#),
+        ];
+        for (request, status, body) in cases {
+            let mut client = TcpStream::connect(&addr).await.unwrap();
+            client.write_all(request.as_bytes()).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", response);
+            assert!(response.ends_with(body), "{}", response);
+        }
+        // 请求头过大
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        let request = format!("GET /slow HTTP/1.1\r\nHost: a\r\nX-Big: {}\r\n\r\n", "x".repeat(10000));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn expect_continue() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.max_body_size = 16;
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        // 接受时先收到100 Continue，再发送请求体
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"PUT /upload HTTP/1.1\r\nHost: a\r\nAuthorization: x\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut interim = [0u8; 25];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        client.write_all(b"hello").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"));
+        // 拒绝时直接返回最终响应并关闭连接，不发送请求体
+        let cases = [
+            ("Authorization: x\r\nExpect: 100-continue\r\nContent-Length: 17\r\n", "413 Content Too Large"),
+            ("Expect: 100-continue\r\nContent-Length: 5\r\n", "401 Unauthorized"),
+            ("Authorization: x\r\nExpect: something\r\nContent-Length: 5\r\n", "417 Expectation Failed"),
+        ];
+        for (headers, status) in cases {
+            let mut client = TcpStream::connect(&addr).await.unwrap();
+            client.write_all(format!("PUT /upload HTTP/1.1\r\nHost: a\r\n{}\r\n", headers).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}", response);
+            assert!(response.contains("Connection: close\r\n"));
+        }
+        // 请求体已经发出时不需要100 Continue
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        client.write_all(b"PUT /upload HTTP/1.1\r\nHost: a\r\nAuthorization: x\r\nExpect: 100-continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"), "{}", response);
+    }
+
+    /// 发送请求后关闭写入，读取全部响应
+    async fn send(addr: &str, request: &[u8]) -> String {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_in_chunked_body() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, HttpSettings::new()).await;
+        let head = b"POST /echo HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for (body, reason) in [(&b"5;\xff\r\nhello\r\n0\r\n\r\n"[..], "无效的分块大小"),
+                               (&b"5\r\nhello\r\n0\r\nX-A: \xff\r\n\r\n"[..], "无效的尾部字段")] {
+            let response = send(&addr, &[&head[..], body].concat()).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+            assert!(response.ends_with(reason), "{}", response);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.max_body_size = 16;
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        let head = "POST /trailers HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+        let cases = [
+            // 块扩展被忽略，尾部字段交给处理器
+            ("3;ext=1\r\nabc\r\n4\r\ndefg\r\n0\r\nX-Sum: 7\r\nX-B: b\r\n\r\n", "200 OK", "abcdefg|x-b=b,x-sum=7"),
+            ("a\r\n0123456789\r\n0\r\n\r\n", "200 OK", "0123456789|"),
+            // 多个块加起来超出max_body_size
+            ("8\r\n12345678\r\n9\r\n123456789\r\n0\r\n\r\n", "413 Content Too Large", "请求体大小超出限制"),
+            ("11\r\n", "413 Content Too Large", "请求体大小超出限制"),
+            ("FFFFFFFFFFFFFFFFFFFF\r\n", "400 Bad Request", "无效的分块大小"),
+            ("3\r\nabcX\r\n0\r\n\r\n", "400 Bad Request", "分块数据损坏"),
+            ("3\r\nabc\r\n0\r\nX-A\r\n\r\n", "400 Bad Request", "无效的尾部字段"),
+        ];
+        for (body, status, expected) in cases {
+            let response = send(&addr, format!("{}{}", head, body).as_bytes()).await;
+            assert!(response.starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}: {}", body, response);
+            assert!(response.ends_with(expected), "{}: {}", body, response);
+        }
+        // chunk-size前后不能有空白
+        for size in [" 3", "3 ", "3 ;ext", "3\t", "", "x", "-3", "0x3", "+3"] {
+            let response = send(&addr, format!("{}{}\r\nabc\r\n0\r\n\r\n", head, size).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}: {}", size, response);
+            assert!(response.ends_with("无效的分块大小"), "{:?}: {}", size, response);
+        }
+        // 只支持单独的chunked
+        let response = send(&addr, b"POST /trailers HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, HttpSettings::new()).await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        // 请求体之后紧跟下一个请求，最后一个请求头只发送了一部分
+        client.write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n\
+POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabc\
+POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nxyzGET /sl").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"ow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let bodies: Vec<&str> = response.split("HTTP/1.1 200 OK\r\n").skip(1)
+            .map(|r| r.split_once("\r\n\r\n").unwrap().1)
+            .collect();
+        assert_eq!(bodies, ["done", "abc", "xyz", "done"]);
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_flush_at_limit() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.max_pipelined_requests = 2;
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        let mut client = TcpStream::connect(&addr).await.unwrap();
+        // 第三个请求的请求体还没有发送，排队的两个响应达到上限后先写出
+        client.write_all(b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\nGET /slow HTTP/1.1\r\nHost: a\r\n\r\n\
+POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0u8; 1024];
+        while response.windows(4).filter(|w| w == b"done").count() < 2 {
+            let length = timeout(Duration::from_secs(5), client.read(&mut buf)).await.unwrap().unwrap();
+            assert_ne!(length, 0);
+            response.extend_from_slice(&buf[..length]);
+        }
+        client.write_all(b"abc").await.unwrap();
+        let mut rest = String::new();
+        client.read_to_string(&mut rest).await.unwrap();
+        assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"), "{}", rest);
+        assert!(rest.ends_with("\r\n\r\nabc"));
+    }
+
+    #[tokio::test]
+    async fn keep_alive_connections() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.max_keep_alive_requests = 3;
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        // HTTP/1.0默认关闭，后面的请求不处理
+        let response = send(&addr, b"GET /slow HTTP/1.0\r\n\r\nGET /slow HTTP/1.0\r\n\r\n").await;
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("Connection: close\r\n"));
+        // HTTP/1.0使用keep-alive，HTTP/1.1默认保持，Connection: close之后关闭
+        let response = send(&addr, b"GET /slow HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+GET /slow HTTP/1.1\r\nHost: a\r\n\r\nGET /slow HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n\
+GET /slow HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        let connections: Vec<&str> = response.split("Connection: ").skip(1).map(|r| r.split("\r\n").next().unwrap()).collect();
+        assert_eq!(connections, ["keep-alive", "keep-alive", "close"]);
+        // 达到max_keep_alive_requests后关闭
+        let response = send(&addr, "GET /slow HTTP/1.1\r\nHost: a\r\n\r\n".repeat(5).as_bytes()).await;
+        let connections: Vec<&str> = response.split("Connection: ").skip(1).map(|r| r.split("\r\n").next().unwrap()).collect();
+        assert_eq!(connections, ["keep-alive", "keep-alive", "close"]);
+    }
+
+    #[tokio::test]
+    async fn stream_bodies_for_http_1_0() {
+        let (addr, _tx, _handle) = start(Duration::ZERO, HttpSettings::new()).await;
+        let response = send(&addr, b"GET /stream HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").await;
+        assert!(response.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(response.ends_with("\r\n\r\n8\r\nstreamed\r\n0\r\n\r\n"));
+        // HTTP/1.0不支持分块编码，写完后关闭连接表示结束
+        let response = send(&addr, b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /stream HTTP/1.0\r\n\r\n").await;
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("\r\n\r\nstreamed"));
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads_use_settings() {
+        let mut http_settings = HttpSettings::new();
+        http_settings.multipart_memory_limit = 8;
+        http_settings.max_part_size = 32;
+        let (addr, _tx, _handle) = start(Duration::ZERO, http_settings).await;
+        let form = |file: &str| {
+            let body = format!("--B\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nshort\r\n\
+--B\r\nContent-Disposition: form-data; name=\"f\"; filename=\"f.txt\"\r\n\r\n{}\r\n--B--\r\n", file);
+            format!("POST /form HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\
+Content-Type: multipart/form-data; boundary=B\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+        };
+        // 超过内存限制的部分写入临时文件
+        let response = send(&addr, form("0123456789abcdef").as_bytes()).await;
+        assert!(response.ends_with("\r\n\r\na=short:false,f=0123456789abcdef:true"), "{}", response);
+        let response = send(&addr, form(&"x".repeat(33)).as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{}", response);
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use crate::cookie::{Cookie, CookieKey, SameSite};
+use crate::error::{Error, ErrorPages, Result};
+use crate::handler::BoxFuture;
+use crate::middleware::{Middleware, Next};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::state::State;
+
+/// 会话中保存的值
+pub type SessionData = Map<String, Value>;
+
+/// 会话存储接口
+pub trait SessionStore: Send + Sync {
+    /// 读取会话，不存在或已过期时返回None
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<SessionData>>>;
+    /// 保存会话，`ttl`之后过期
+    fn save<'a>(&'a self, id: &'a str, data: &'a SessionData, ttl: Duration) -> BoxFuture<'a, Result<()>>;
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// 内存中的会话存储，重启后丢失
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, SystemTime)>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<SessionData>>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions.get(id) {
+                Some((data, expires)) if *expires > SystemTime::now() => Ok(Some(data.clone())),
+                Some(_) => {
+                    sessions.remove(id);
+                    Ok(None)
+                }
+                None => Ok(None),
+            }
+        })
+    }
+    fn save<'a>(&'a self, id: &'a str, data: &'a SessionData, ttl: Duration) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let now = SystemTime::now();
+            let mut sessions = self.sessions.lock().unwrap();
+            // 顺便清理过期的会话
+            sessions.retain(|_, (_, expires)| *expires > now);
+            sessions.insert(id.to_string(), (data.clone(), now + ttl));
+            Ok(())
+        })
+    }
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.sessions.lock().unwrap().remove(id);
+            Ok(())
+        })
+    }
+}
+
+static SAVE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// 文件会话存储，每个会话一个JSON文件
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// 目录不存在时创建
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+    /// 只接受生成的ID格式，防止路径穿越
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        if !is_session_id(id) {
+            return Err(Error::invalid("无效的会话ID"));
+        }
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<SessionData>>> {
+        Box::pin(async move {
+            let path = self.path(id)?;
+            let content = match tokio::fs::read(&path).await {
+                Ok(content) => content,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            let file = serde_json::from_slice::<Value>(&content).ok().and_then(|mut file| {
+                let expires = file["expires"].as_u64()?;
+                match file["data"].take() {
+                    Value::Object(data) => Some((expires, data)),
+                    _ => None,
+                }
+            });
+            match file {
+                Some((expires, data)) if UNIX_EPOCH + Duration::from_secs(expires) > SystemTime::now() => Ok(Some(data)),
+                // 过期或损坏的文件都删除，损坏的文件不能让这个用户的每个请求都失败
+                _ => {
+                    let _ = tokio::fs::remove_file(&path).await;
+                    Ok(None)
+                }
+            }
+        })
+    }
+    fn save<'a>(&'a self, id: &'a str, data: &'a SessionData, ttl: Duration) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.path(id)?;
+            let expires = (SystemTime::now() + ttl).duration_since(UNIX_EPOCH)?.as_secs();
+            let content = serde_json::to_vec(&json!({ "expires": expires, "data": data }))?;
+            // 先写临时文件再改名，避免读到写了一半的文件
+            // 同一个会话的请求可能同时保存，每次使用不同的临时文件
+            let tmp = path.with_extension(format!("{}.tmp", SAVE_ID.fetch_add(1, Ordering::Relaxed)));
+            tokio::fs::write(&tmp, content).await?;
+            if let Err(err) = tokio::fs::rename(&tmp, &path).await {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                return Err(err.into());
+            }
+            Ok(())
+        })
+    }
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(id)?).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// 生成256位的随机会话ID
+fn new_session_id() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn is_session_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// 当前请求的会话，由[SessionMiddleware]放入请求的extensions，通过[HttpRequest::session]获取
+#[derive(Clone, Debug)]
+pub struct Session {
+    inner: Arc<Mutex<SessionInner>>,
+}
+
+#[derive(Debug)]
+struct SessionInner {
+    id: String,
+    data: SessionData,
+    // 存储中还没有这个会话
+    is_new: bool,
+    changed: bool,
+    // 轮换之前的ID，需要从存储中删除
+    rotated_from: Option<String>,
+    destroyed: bool,
+}
+
+impl Session {
+    fn new(id: String, data: Option<SessionData>) -> Self {
+        let is_new = data.is_none();
+        Self {
+            inner: Arc::new(Mutex::new(SessionInner {
+                id,
+                data: data.unwrap_or_default(),
+                is_new,
+                changed: false,
+                rotated_from: None,
+                destroyed: false,
+            })),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, SessionInner> {
+        self.inner.lock().unwrap()
+    }
+
+    pub fn id(&self) -> String {
+        self.lock().id.clone()
+    }
+    /// 读取值，不存在或类型不匹配时返回None
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let inner = self.lock();
+        serde_json::from_value(inner.data.get(key)?.clone()).ok()
+    }
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut inner = self.lock();
+        inner.data.insert(key.to_string(), value);
+        inner.changed = true;
+        Ok(())
+    }
+    pub fn remove(&self, key: &str) -> bool {
+        let mut inner = self.lock();
+        let removed = inner.data.remove(key).is_some();
+        inner.changed |= removed;
+        removed
+    }
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.data.clear();
+        inner.changed = true;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lock().data.is_empty()
+    }
+    /// 更换会话ID并保留数据，登录等权限变化后调用，防止会话固定攻击
+    pub fn rotate(&self) {
+        let mut inner = self.lock();
+        let old = std::mem::replace(&mut inner.id, new_session_id());
+        if !inner.is_new && inner.rotated_from.is_none() {
+            inner.rotated_from = Some(old);
+        }
+        inner.is_new = true;
+        inner.changed = true;
+    }
+    /// 删除会话，响应中会清除cookie
+    pub fn destroy(&self) {
+        let mut inner = self.lock();
+        inner.data.clear();
+        inner.destroyed = true;
+    }
+}
+
+/// 会话中间件，根据cookie中的会话ID加载会话，请求处理完后保存修改
+/// 会话在最后一次修改`ttl`之后过期
+pub struct SessionMiddleware {
+    store: Box<dyn SessionStore>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+    key: Option<CookieKey>,
+}
+
+impl SessionMiddleware {
+    /// 默认cookie名称为sid，有效期1天
+    pub fn new(store: impl SessionStore + 'static) -> Self {
+        Self {
+            store: Box::new(store),
+            cookie_name: "sid".to_string(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            key: None,
+        }
+    }
+    pub fn cookie_name(mut self, name: impl Into<String>) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+    /// 只通过HTTPS发送cookie
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+    /// 对cookie中的会话ID签名
+    pub fn signed(mut self, key: CookieKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    fn cookie(&self, value: impl Into<String>) -> Cookie {
+        Cookie::new(self.cookie_name.clone(), value)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+    }
+
+    /// 从cookie获取会话ID，签名不对或格式不对时忽略
+    fn session_id<'r>(&self, req: &'r HttpRequest) -> Option<&'r str> {
+        let id = match &self.key {
+            Some(key) => req.cookies().get_signed(&self.cookie_name, key)?,
+            None => req.cookie(&self.cookie_name)?,
+        };
+        is_session_id(id).then_some(id)
+    }
+
+    async fn load(&self, req: &HttpRequest<'_>) -> Result<Session> {
+        if let Some(id) = self.session_id(req) {
+            if let Some(data) = self.store.load(id).await? {
+                return Ok(Session::new(id.to_string(), Some(data)));
+            }
+        }
+        Ok(Session::new(new_session_id(), None))
+    }
+
+    /// 保存会话的修改，需要时设置或清除cookie
+    async fn commit(&self, session: &Session, has_cookie: bool, response: &mut HttpResponse) -> Result<()> {
+        let (id, data, is_new, changed, rotated_from, destroyed) = {
+            let inner = session.lock();
+            (inner.id.clone(), inner.data.clone(), inner.is_new, inner.changed, inner.rotated_from.clone(), inner.destroyed)
+        };
+        if let Some(old) = rotated_from {
+            self.store.remove(&old).await?;
+        }
+        if destroyed || (changed && data.is_empty()) {
+            if !is_new {
+                self.store.remove(&id).await?;
+            }
+            if has_cookie {
+                response.set_cookie(&self.cookie("").max_age(Duration::ZERO).expires(UNIX_EPOCH));
+            }
+        } else if changed {
+            self.store.save(&id, &data, self.ttl).await?;
+            let cookie = self.cookie(id).max_age(self.ttl);
+            let cookie = match &self.key {
+                Some(key) => key.sign(cookie),
+                None => cookie,
+            };
+            response.set_cookie(&cookie);
+        }
+        Ok(())
+    }
+}
+
+impl Middleware for SessionMiddleware {
+    fn handle<'a>(&'a self, mut req: HttpRequest<'a>, _state: &'a State, next: Next<'a>) -> BoxFuture<'a, HttpResponse> {
+        Box::pin(async move {
+            let session = match self.load(&req).await {
+                Ok(session) => session,
+                Err(err) => {
+                    println!("{}", err);
+                    return req.error_response(&err);
+                }
+            };
+            let has_cookie = req.cookie(&self.cookie_name).is_some();
+            // 请求交给后续处理后，保存失败时使用同样的错误页面
+            let pages = req.extensions().get::<ErrorPages>().cloned().unwrap_or_default();
+            req.extensions_mut().insert(session.clone());
+            let mut response = next.run(req).await;
+            if let Err(err) = self.commit(&session, has_cookie, &mut response).await {
+                println!("{}", err);
+                return pages.render(&err);
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::task::JoinSet;
+    use crate::cookie::CookieKey;
+    use crate::error::{Error, ErrorPages};
+    use crate::handler::{handler_fn, BoxFuture};
+    use crate::request::HttpRequest;
+    use crate::response::{Body, HttpResponse, HttpStatus};
+    use crate::router::Router;
+    use super::{FileStore, MemoryStore, SessionData, SessionMiddleware, SessionStore};
+
+    fn router(sessions: SessionMiddleware) -> Router {
+        let mut router = Router::new();
+        router.wrap(sessions);
+        router.get("/login", handler_fn(|req, _| Box::pin(async move {
+            let session = req.session().unwrap();
+            session.rotate();
+            session.insert("user", "张三").unwrap();
+            HttpResponse::new(HttpStatus::Ok, None, None)
+        }))).unwrap();
+        router.get("/me", handler_fn(|req, _| Box::pin(async move {
+            let user = req.session().unwrap().get::<String>("user").unwrap_or_default();
+            HttpResponse::new(HttpStatus::Ok, None, Some(user.into_bytes()))
+        }))).unwrap();
+        router.get("/logout", handler_fn(|req, _| Box::pin(async move {
+            req.session().unwrap().destroy();
+            HttpResponse::new(HttpStatus::Ok, None, None)
+        }))).unwrap();
+        router
+    }
+
+    async fn get(router: &Router, url: &str, cookie: Option<&str>) -> HttpResponse {
+        let cookie = cookie.map(|c| format!("Cookie: {}\r\n", c)).unwrap_or_default();
+        let raw = format!("GET {} HTTP/1.1\r\n{}\r\n", url, cookie);
+        let req = HttpRequest::from(&raw, Vec::new(), "127.0.0.1").unwrap();
+        router.route(req).await
+    }
+
+    fn body(response: &HttpResponse) -> &[u8] {
+        match response.body() {
+            Body::Bytes(bytes) => bytes,
+            _ => b"",
+        }
+    }
+
+    /// Set-Cookie中的 名称=值 部分
+    fn cookie_pair(response: &HttpResponse) -> Option<String> {
+        let set_cookie = response.header("set-cookie")?;
+        Some(set_cookie.split(';').next().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn login_rotate_and_logout() {
+        let router = router(SessionMiddleware::new(MemoryStore::new()));
+        // 只读取时不创建会话
+        let response = get(&router, "/me", None).await;
+        assert_eq!(response.header("set-cookie"), None);
+
+        let response = get(&router, "/login", None).await;
+        let first = cookie_pair(&response).unwrap();
+        assert!(response.header("set-cookie").unwrap().contains("HttpOnly; SameSite=Lax"));
+        assert_eq!(body(&get(&router, "/me", Some(&first)).await), "张三".as_bytes());
+
+        // 再次登录会更换ID，旧ID失效
+        let response = get(&router, "/login", Some(&first)).await;
+        let second = cookie_pair(&response).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(body(&get(&router, "/me", Some(&first)).await), b"");
+        assert_eq!(body(&get(&router, "/me", Some(&second)).await), "张三".as_bytes());
+
+        let response = get(&router, "/logout", Some(&second)).await;
+        assert!(response.header("set-cookie").unwrap().contains("Max-Age=0"));
+        assert_eq!(body(&get(&router, "/me", Some(&second)).await), b"");
+    }
+
+    #[tokio::test]
+    async fn signed_session_cookie() {
+        let key = CookieKey::new([1u8; 32]).unwrap();
+        let router = router(SessionMiddleware::new(MemoryStore::new()).cookie_name("app").signed(key));
+        let cookie = cookie_pair(&get(&router, "/login", None).await).unwrap();
+        assert!(cookie.starts_with("app="));
+        assert_eq!(body(&get(&router, "/me", Some(&cookie)).await), "张三".as_bytes());
+        // 去掉签名后不能使用
+        let unsigned = cookie.rsplit_once('.').unwrap().0;
+        assert_eq!(body(&get(&router, "/me", Some(unsigned)).await), b"");
+    }
+
+    #[tokio::test]
+    async fn stores_expire_sessions() {
+        let dir = std::env::temp_dir().join(format!("my-http-server-sessions-{}", std::process::id()));
+        let stores: [Box<dyn SessionStore>; 2] = [Box::new(MemoryStore::new()), Box::new(FileStore::new(&dir).unwrap())];
+        let id = "ab".repeat(32);
+        let mut data = SessionData::new();
+        data.insert("n".to_string(), 1.into());
+        for store in stores {
+            store.save(&id, &data, Duration::from_secs(60)).await.unwrap();
+            assert_eq!(store.load(&id).await.unwrap(), Some(data.clone()));
+            store.save(&id, &data, Duration::ZERO).await.unwrap();
+            assert_eq!(store.load(&id).await.unwrap(), None);
+            store.remove(&id).await.unwrap();
+        }
+        assert!(FileStore::new(&dir).unwrap().load("../../etc/passwd").await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_concurrent_and_corrupt() {
+        let dir = std::env::temp_dir().join(format!("my-http-server-sessions-concurrent-{}", std::process::id()));
+        let store = Arc::new(FileStore::new(&dir).unwrap());
+        let id = "cd".repeat(32);
+        // 同一个会话同时保存，都应该成功
+        let mut saves = JoinSet::new();
+        for n in 0..32 {
+            let (store, id) = (store.clone(), id.clone());
+            saves.spawn(async move {
+                let mut data = SessionData::new();
+                data.insert("n".to_string(), n.into());
+                store.save(&id, &data, Duration::from_secs(60)).await
+            });
+        }
+        while let Some(result) = saves.join_next().await {
+            result.unwrap().unwrap();
+        }
+        assert!(store.load(&id).await.unwrap().is_some());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        // 损坏的文件当作不存在，并且被删除
+        let path = dir.join(format!("{}.json", id));
+        for content in ["{不是JSON", r#"{"expires": "x", "data": {}}"#, r#"{"expires": 99999999999, "data": 1}"#] {
+            std::fs::write(&path, content).unwrap();
+            assert_eq!(store.load(&id).await.unwrap(), None);
+            assert!(!path.exists());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// 总是失败的存储，错误中包含不能返回给客户端的内部信息
+    struct BrokenStore;
+
+    impl SessionStore for BrokenStore {
+        fn load<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, crate::error::Result<Option<SessionData>>> {
+            Box::pin(async { Err(Error::invalid("/var/lib/sessions 无法读取")) })
+        }
+        fn save<'a>(&'a self, _id: &'a str, _data: &'a SessionData, _ttl: Duration) -> BoxFuture<'a, crate::error::Result<()>> {
+            Box::pin(async { Err(Error::invalid("/var/lib/sessions 无法写入")) })
+        }
+        fn remove<'a>(&'a self, _id: &'a str) -> BoxFuture<'a, crate::error::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn store_errors_do_not_leak() {
+        let router = router(SessionMiddleware::new(BrokenStore));
+        let cookie = format!("sid={}", "a".repeat(64));
+        for (url, cookie) in [("/me", Some(cookie.as_str())), ("/login", None)] {
+            let response = get(&router, url, cookie).await;
+            assert_eq!(response.status(), &HttpStatus::InternalServerError);
+            assert_eq!(body(&response), b"Internal Server Error");
+        }
+        // 使用服务器配置的错误页面
+        let mut req = HttpRequest::from("GET /login HTTP/1.1\r\n\r\n", Vec::new(), "127.0.0.1").unwrap();
+        req.extensions_mut().insert(ErrorPages::new().json());
+        let response = router.route(req).await;
+        assert_eq!(body(&response), br#"{"code":500,"msg":"Internal Server Error"}"#);
+    }
+}
@@ -0,0 +1,29 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+/// 应用状态，按类型存取，处理器通过它共享配置、连接池、计数器等
+#[derive(Default)]
+pub struct State {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// 存入一个值，同类型的旧值会被替换
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref::<T>())
+    }
+}
+
+impl Debug for State {
+    fn fmt(&self, formatter: &mut Formatter) -> FmtResult {
+        write!(formatter, "State({})", self.values.len())
+    }
+}
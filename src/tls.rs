@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use crate::error::{Error, Result};
+
+/// 证书和私钥的PEM文件路径
+#[derive(Clone, Debug)]
+struct CertFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// HTTPS设置
+/// ```ignore
+/// let tls = TlsSettings::new()
+///     .cert("certs/default.pem", "certs/default.key")
+///     .sni_cert("api.example.com", "certs/api.pem", "certs/api.key");
+/// let server = Server::new("0.0.0.0:443", http_settings, router).tls(tls)?.http("0.0.0.0:80", true);
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    default: Option<CertFiles>,
+    hosts: Vec<(String, CertFiles)>,
+    pub(crate) reload_interval: Option<Duration>,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsSettings {
+    /// 默认每30秒检查一次证书文件是否修改
+    pub fn new() -> Self {
+        Self { default: None, hosts: Vec::new(), reload_interval: Some(Duration::from_secs(30)) }
+    }
+    /// 默认证书，客户端没有发送SNI或没有匹配的主机名时使用
+    pub fn cert(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.default = Some(CertFiles { cert: cert.into(), key: key.into() });
+        self
+    }
+    /// 按SNI主机名选择的证书，主机名可以是 *.example.com 形式的通配符
+    pub fn sni_cert(mut self, hostname: &str, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.hosts.push((hostname.to_ascii_lowercase(), CertFiles { cert: cert.into(), key: key.into() }));
+        self
+    }
+    /// 检查证书文件是否修改的间隔，None时不自动重新加载
+    pub fn reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    fn files(&self) -> impl Iterator<Item=&CertFiles> {
+        self.default.iter().chain(self.hosts.iter().map(|(_, files)| files))
+    }
+}
+
+/// 已加载的证书
+#[derive(Debug)]
+struct Certs {
+    default: Option<Arc<CertifiedKey>>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    // 加载时证书和私钥文件的修改时间
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// 按SNI选择证书，证书可以在运行时重新加载
+#[derive(Debug)]
+pub struct CertResolver {
+    settings: TlsSettings,
+    certs: RwLock<Arc<Certs>>,
+}
+
+impl CertResolver {
+    pub fn new(settings: &TlsSettings) -> Result<Arc<Self>> {
+        if settings.files().next().is_none() {
+            return Err(Error::invalid("没有配置证书"));
+        }
+        let certs = load_certs(settings)?;
+        Ok(Arc::new(Self { settings: settings.clone(), certs: RwLock::new(Arc::new(certs)) }))
+    }
+
+    /// 重新加载所有证书，失败时继续使用旧的证书
+    pub fn reload(&self) -> Result<()> {
+        let certs = load_certs(&self.settings)?;
+        *self.certs.write().unwrap() = Arc::new(certs);
+        Ok(())
+    }
+    /// 文件修改过时重新加载，返回是否重新加载了
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = modified_times(&self.settings);
+        if modified == self.certs.read().unwrap().modified {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+    /// 定期检查证书文件
+    pub(crate) async fn watch(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            // 读取文件会阻塞，放到阻塞线程中执行
+            let resolver = self.clone();
+            let reloaded = tokio::task::spawn_blocking(move || resolver.reload_if_changed()).await
+                .map_err(Error::other).and_then(|reloaded| reloaded);
+            match reloaded {
+                Ok(true) => println!("证书已重新加载"),
+                Ok(false) => {}
+                Err(err) => println!("证书重新加载失败: {}", err),
+            }
+        }
+    }
+
+    /// 先精确匹配主机名，再匹配通配符，最后使用默认证书
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap().clone();
+        if let Some(name) = server_name.map(str::to_ascii_lowercase) {
+            if let Some(cert) = certs.hosts.get(&name) {
+                return Some(cert.clone());
+            }
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(cert) = certs.hosts.get(&format!("*.{}", parent)) {
+                    return Some(cert.clone());
+                }
+            }
+        }
+        certs.default.clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+/// 使用`resolver`中证书的TLS acceptor，`http2`为true时通过ALPN协商h2
+pub(crate) fn acceptor(resolver: Arc<CertResolver>, http2: bool) -> TlsAcceptor {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = match http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+    TlsAcceptor::from(Arc::new(config))
+}
+
+fn load_certs(settings: &TlsSettings) -> Result<Certs> {
+    // 先记录修改时间，加载期间文件又被修改时下次检查会再加载一次
+    let modified = modified_times(settings);
+    let default = settings.default.as_ref().map(load_cert).transpose()?;
+    let mut hosts = HashMap::new();
+    for (hostname, files) in &settings.hosts {
+        hosts.insert(hostname.clone(), load_cert(files)?);
+    }
+    Ok(Certs { default, hosts, modified })
+}
+
+fn modified_times(settings: &TlsSettings) -> Vec<Option<SystemTime>> {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    settings.files()
+        .flat_map(|files| [modified(&files.cert), modified(&files.key)])
+        .collect()
+}
+
+/// 读取PEM格式的证书链和私钥
+fn load_cert(files: &CertFiles) -> Result<Arc<CertifiedKey>> {
+    let open = |path: &Path| File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::invalid(format!("无法打开 {}: {}", path.display(), err)));
+    let certs = rustls_pemfile::certs(&mut open(&files.cert)?)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::invalid(format!("{} 中没有证书", files.cert.display())));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&files.key)?)?
+        .ok_or_else(|| Error::invalid(format!("{} 中没有私钥", files.key.display())))?;
+    let key = ring::sign::any_supported_type(&key)?;
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match()
+        .map_err(|_| Error::invalid(format!("{} 与 {} 不匹配", files.key.display(), files.cert.display())))?;
+    Ok(Arc::new(certified))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+    use crate::handler::HelloHandler;
+    use crate::router::Router;
+    use crate::server::{serve_conn, HttpSettings, Protocol, Shutdown};
+    use super::{acceptor, CertResolver, TlsSettings};
+
+    /// 生成自签名证书，返回证书的DER
+    fn self_signed(dir: &Path, name: &str) -> CertificateDer<'static> {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        std::fs::write(dir.join(format!("{}.pem", name)), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join(format!("{}.key", name)), cert.key_pair.serialize_pem()).unwrap();
+        cert.cert.der().clone()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("my-http-server-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn settings(dir: &Path) -> TlsSettings {
+        TlsSettings::new()
+            .cert(dir.join("a.test.pem"), dir.join("a.test.key"))
+            .sni_cert("B.test", dir.join("b.test.pem"), dir.join("b.test.key"))
+            .sni_cert("*.c.test", dir.join("x.c.test.pem"), dir.join("x.c.test.key"))
+    }
+
+    /// 通过TLS发送一个请求，返回服务器证书和响应
+    async fn request(resolver: Arc<CertResolver>, roots: &[CertificateDer<'static>], name: &str) -> (CertificateDer<'static>, String) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let stream = acceptor(resolver, false).accept(server).await.unwrap();
+            let mut router = Router::new();
+            router.get("/hello", HelloHandler).unwrap();
+            let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            serve_conn(Arc::new(HttpSettings::new()), Arc::new(router), stream, addr, Protocol::Tls, Shutdown::never()).await;
+        });
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add(root.clone()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let server_name = ServerName::try_from(name.to_string()).unwrap();
+        let mut stream = connector.connect(server_name, client).await.unwrap();
+        let raw = format!("GET /hello HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", name);
+        stream.write_all(raw.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let cert = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+        (cert, response)
+    }
+
+    #[tokio::test]
+    async fn selects_certificate_by_sni() {
+        let dir = temp_dir("sni");
+        let roots = ["a.test", "b.test", "x.c.test"].map(|name| self_signed(&dir, name));
+        let resolver = CertResolver::new(&settings(&dir)).unwrap();
+        let (cert, response) = request(resolver.clone(), &roots, "b.test").await;
+        assert_eq!(cert, roots[1]);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\"code\":200,\"msg\":\"OK\"}"));
+        assert_eq!(request(resolver.clone(), &roots, "a.test").await.0, roots[0]);
+        assert_eq!(request(resolver.clone(), &roots, "x.c.test").await.0, roots[2]);
+        // 没有匹配的主机名时使用默认证书
+        let default = resolver.lookup(Some("unknown.test")).unwrap();
+        assert_eq!(default.cert[0], roots[0]);
+        assert_eq!(resolver.lookup(None).unwrap().cert[0], roots[0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_certificates() {
+        let dir = temp_dir("reload");
+        let roots = ["a.test", "b.test", "x.c.test"].map(|name| self_signed(&dir, name));
+        let resolver = CertResolver::new(&settings(&dir)).unwrap();
+        assert!(!resolver.reload_if_changed().unwrap());
+        let renewed = self_signed(&dir, "b.test");
+        resolver.reload().unwrap();
+        assert_eq!(request(resolver.clone(), std::slice::from_ref(&renewed), "b.test").await.0, renewed);
+        // 加载失败时继续使用旧的证书
+        std::fs::write(dir.join("b.test.key"), "broken").unwrap();
+        assert!(resolver.reload().is_err());
+        assert_eq!(resolver.lookup(Some("b.test")).unwrap().cert[0], renewed);
+        assert_ne!(renewed, roots[1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watches_certificate_files() {
+        let dir = temp_dir("watch");
+        for name in ["a.test", "b.test", "x.c.test"] {
+            self_signed(&dir, name);
+        }
+        let resolver = CertResolver::new(&settings(&dir)).unwrap();
+        let watcher = tokio::spawn(resolver.clone().watch(Duration::from_millis(10)));
+        let renewed = self_signed(&dir, "b.test");
+        for _ in 0..500 {
+            if resolver.lookup(Some("b.test")).unwrap().cert[0] == renewed {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(resolver.lookup(Some("b.test")).unwrap().cert[0], renewed);
+        watcher.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_or_mismatched_files() {
+        let dir = temp_dir("invalid");
+        assert!(CertResolver::new(&TlsSettings::new()).is_err());
+        assert!(CertResolver::new(&TlsSettings::new().cert(dir.join("none.pem"), dir.join("none.key"))).is_err());
+        self_signed(&dir, "a.test");
+        self_signed(&dir, "b.test");
+        let mismatched = TlsSettings::new().cert(dir.join("a.test.pem"), dir.join("b.test.key"));
+        assert!(CertResolver::new(&mismatched).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,12 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 /// 按照指定分隔符分割u8数组
 pub fn split<D: AsRef<[u8]>>(data: &D, separator: impl AsRef<[u8]>) -> Vec<&[u8]> {
     let sep = separator.as_ref();
     let mut data = data.as_ref();
     // 查找分隔符位置
     let mut buf = Vec::new();
-    while let Some(pos) = scan(&data, &sep) {
+    while let Some(pos) = scan(data, sep) {
         // 分割数据
         let (split, rest) = data.split_at(pos);
         buf.push(split);
@@ -19,19 +21,73 @@ pub fn split<D: AsRef<[u8]>>(data: &D, separator: impl AsRef<[u8]>) -> Vec<&[u8]
 pub fn scan(data: impl AsRef<[u8]>, pattern: impl AsRef<[u8]>) -> Option<usize> {
     let data: &[u8] = data.as_ref();
     let pat: &[u8] = pattern.as_ref();
-    if pat.len() > data.len() {
+    if pat.is_empty() || pat.len() > data.len() {
         return None;
     }
-    let mut found = 0usize;
-    for (i, &d) in data.iter().enumerate() {
-        if d == pat[found] {
-            found += 1;
-            if found == pat.len() {
-                return Some(i + 1 - pat.len());
+    data.windows(pat.len()).position(|w| w == pat)
+}
+
+/// 是否是RFC 9110定义的token，用于方法名和请求头名称
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_tchar)
+}
+
+/// token中允许的字符
+pub fn is_tchar(b: u8) -> bool {
+    TCHAR[b as usize]
+}
+
+// 查表比逐个比较快，请求头解析时每个字节都要检查
+const TCHAR: [bool; 256] = {
+    let mut table = [false; 256];
+    let mut i = 0;
+    while i < 256 {
+        let b = i as u8;
+        table[i] = b.is_ascii_alphanumeric() || matches!(b, b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*'
+            | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~');
+        i += 1;
+    }
+    table
+};
+
+/// 百分号解码，不合法的编码保持原样
+pub fn percent_decode(data: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == b'%' && i + 2 < data.len() {
+            if let (Some(h), Some(l)) = (hex(data[i + 1]), hex(data[i + 2])) {
+                decoded.push(h << 4 | l);
+                i += 3;
+                continue;
             }
-        } else {
-            found = 0;
         }
+        decoded.push(data[i]);
+        i += 1;
     }
-    None
-}
\ No newline at end of file
+    decoded
+}
+
+/// 格式化为http日期，如 Sun, 06 Nov 1994 08:49:37 GMT
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    // 早于1970年的时间按1970年处理
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    // 天数转换为公历日期
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[(days % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+            secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60)
+}